@@ -3,7 +3,12 @@ use thiserror::Error;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Claims {
+    /// The channel ID, empty for pattern tokens.
+    #[serde(default)]
     pub cid: String,
+    /// A channel pattern, for tokens subscribing to every matching channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpt: Option<String>,
 }
 
 #[derive(Error, Debug)]
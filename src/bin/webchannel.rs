@@ -5,12 +5,14 @@ static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 use anyhow::{Context, Result};
 use clap::{App, Arg};
+use futures::{select, FutureExt};
 use std::env;
 use std::path::Path;
-use tracing::info;
+use std::time::Duration;
+use tracing::{info, warn};
 use warp::Filter;
 
-use webchannel::{environment::Environment, filters, metrics, problem, settings};
+use webchannel::{environment::Environment, filters, metrics, problem, server, settings, shutdown};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -49,19 +51,56 @@ async fn main() -> Result<()> {
     let cors = cors_builder.build();
 
     let env = Environment::new(settings.clone()).await?;
+    let shutdown = env.shutdown.clone();
 
     let api = filters::webchannel(env.clone())
+        .or(filters::pusher(env.clone()))
         .or(filters::health())
+        .or(filters::ready(shutdown.clone()))
         .or(filters::metrics(env.settings.metrics))
         .recover(problem::unpack)
         .with(cors);
 
-    let routes = api
-        .with(warp::log("webchannel"))
-        .with(warp::log::custom(metrics::warp_log_metrics));
+    let routes = api.with(warp::log::custom(metrics::warp_log_metrics));
 
-    let listen_addr = settings.server.listen_address;
-    info!("Starting server, listening on http://{}", listen_addr);
-    warp::serve(routes).run(listen_addr).await;
+    let scheme = match settings.server.tls {
+        Some(_) => "https",
+        None => "http",
+    };
+    info!(
+        "Starting server, listening on {}://{}",
+        scheme, settings.server.listen_address
+    );
+
+    let draining = shutdown.clone();
+    tokio::spawn(async move {
+        match shutdown::signalled().await {
+            Ok(()) => {
+                info!("Shutting down, draining connections");
+                draining.start();
+            }
+            Err(e) => warn!("Failed listening for shutdown signals: {}", e),
+        }
+    });
+
+    // Keeps accepting requests other than upgrades until WebSockets are closed, so
+    // publishes and readiness checks are still answered.
+    let drained = shutdown.clone();
+    let serve = server::run(warp::service(routes), &settings.server, async move {
+        drained.draining().await;
+        drained.drained().await;
+    });
+    let drain_timeout = Duration::from_secs(settings.shutdown.drain_timeout);
+    let deadline = async {
+        shutdown.draining().await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+    select! {
+        result = serve.fuse() => result?,
+        _ = deadline.fuse() => warn!(
+            "Drain timeout elapsed, exiting with {} WebSocket connections open",
+            shutdown.open_connections()
+        ),
+    }
     Ok(())
 }
@@ -0,0 +1,153 @@
+use super::Message;
+use crate::metrics;
+use futures::{Stream, StreamExt};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::warn;
+
+type Channels<T> = Arc<Mutex<HashMap<String, broadcast::Sender<T>>>>;
+type OnEmpty = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Fans channel messages out to every subscriber within this process.
+///
+/// Pattern subscriptions fan out messages tagged with their channel, keyed by the pattern.
+pub struct Fanout<T = Message> {
+    capacity: usize,
+    channels: Channels<T>,
+    on_empty: Option<OnEmpty>,
+}
+
+impl<T> Clone for Fanout<T> {
+    fn clone(&self) -> Self {
+        Self {
+            capacity: self.capacity,
+            channels: self.channels.clone(),
+            on_empty: self.on_empty.clone(),
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> Fanout<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            channels: Default::default(),
+            on_empty: None,
+        }
+    }
+
+    /// Calls `on_empty` with the channel ID after its last local subscriber leaves.
+    ///
+    /// The callback runs while the channel map is locked, so it must not block, and must not
+    /// call back into the fan-out.
+    pub fn with_on_empty(mut self, on_empty: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_empty = Some(Arc::new(on_empty));
+        self
+    }
+
+    pub fn subscribe(&self, channel_id: &str) -> Subscription<T> {
+        let mut channels = self.channels.lock();
+        let rx = channels
+            .entry(channel_id.to_owned())
+            .or_insert_with(|| {
+                metrics::CHANNELS_SUBSCRIBED.inc();
+                broadcast::channel(self.capacity).0
+            })
+            .subscribe();
+        metrics::subscriber_added(channel_id);
+
+        Subscription {
+            messages: BroadcastStream::new(rx),
+            guard: SubscriberGuard {
+                channel_id: channel_id.to_owned(),
+                fanout: self.clone(),
+            },
+        }
+    }
+
+    /// Sends a message to the channel's local subscribers, returning how many received it.
+    pub fn send(&self, channel_id: &str, message: T) -> usize {
+        match self.channels.lock().get(channel_id) {
+            // An error only means there are no receivers left.
+            Some(tx) => tx.send(message).unwrap_or_default(),
+            None => 0,
+        }
+    }
+
+    /// Sends a message to every channel whose key matches, returning how many received it.
+    pub fn send_matching(&self, matches: impl Fn(&str) -> bool, message: T) -> usize {
+        self.channels
+            .lock()
+            .iter()
+            .filter(|(key, _)| matches(key))
+            .map(|(_, tx)| tx.send(message.clone()).unwrap_or_default())
+            .sum()
+    }
+
+    pub fn subscribers(&self, channel_id: &str) -> usize {
+        self.channels
+            .lock()
+            .get(channel_id)
+            .map(|tx| tx.receiver_count())
+            .unwrap_or_default()
+    }
+
+    /// Drops a channel, ending its local subscriptions.
+    pub fn close(&self, channel_id: &str) {
+        if self.channels.lock().remove(channel_id).is_some() {
+            metrics::CHANNELS_SUBSCRIBED.dec();
+        }
+    }
+}
+
+/// Removes the channel once its last local subscriber is dropped.
+struct SubscriberGuard<T> {
+    channel_id: String,
+    fanout: Fanout<T>,
+}
+
+impl<T> Drop for SubscriberGuard<T> {
+    fn drop(&mut self) {
+        let mut channels = self.fanout.channels.lock();
+        metrics::subscriber_removed(&self.channel_id);
+        if let Some(tx) = channels.get(&self.channel_id) {
+            if tx.receiver_count() == 0 {
+                channels.remove(&self.channel_id);
+                metrics::CHANNELS_SUBSCRIBED.dec();
+                if let Some(on_empty) = &self.fanout.on_empty {
+                    on_empty(&self.channel_id);
+                }
+            }
+        }
+    }
+}
+
+pub struct Subscription<T = Message> {
+    // Field order matters, the receiver must drop before the guard checks for receivers.
+    messages: BroadcastStream<T>,
+    guard: SubscriberGuard<T>,
+}
+
+impl<T: Clone + Send + 'static> Stream for Subscription<T> {
+    type Item = anyhow::Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match futures::ready!(self.messages.poll_next_unpin(cx)) {
+                Some(Ok(message)) => return Poll::Ready(Some(Ok(message))),
+                Some(Err(BroadcastStreamRecvError::Lagged(skipped))) => {
+                    warn!(
+                        "Subscriber lagged on channel {:?}, skipped {} messages",
+                        self.guard.channel_id, skipped
+                    );
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
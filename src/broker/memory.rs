@@ -0,0 +1,143 @@
+use super::{
+    fanout::Fanout, Broker, ChannelPattern, ChannelStats, HistoryStart, Message, MessageId,
+    MessageStream, PatternStream, Published,
+};
+use crate::settings;
+use async_trait::async_trait;
+use futures::StreamExt;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+struct ChannelHistory {
+    messages: VecDeque<Message>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct History {
+    last_id: Option<MessageId>,
+    last_pruned: Option<Instant>,
+    channels: HashMap<String, ChannelHistory>,
+}
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+impl History {
+    fn next_id(&mut self) -> MessageId {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let id = match self.last_id {
+            Some(last) if last.millis >= millis => MessageId {
+                millis: last.millis,
+                seq: last.seq + 1,
+            },
+            _ => MessageId { millis, seq: 0 },
+        };
+        self.last_id = Some(id);
+        id
+    }
+}
+
+/// An in-process broker, for single node deployments.
+pub struct MemoryBroker {
+    fanout: Fanout,
+    patterns: Fanout<(String, Message)>,
+    history_settings: settings::History,
+    history: Mutex<History>,
+}
+
+impl MemoryBroker {
+    pub fn new(settings: &settings::Memory, history_settings: &settings::History) -> Self {
+        Self {
+            fanout: Fanout::new(settings.channel_capacity),
+            patterns: Fanout::new(settings.channel_capacity),
+            history_settings: history_settings.clone(),
+            history: Default::default(),
+        }
+    }
+
+    fn append_history(&self, channel_id: &str, message: &mut Message) {
+        let mut history = self.history.lock();
+        let id = history.next_id();
+        message.id = Some(id);
+
+        let now = Instant::now();
+        let ttl = Duration::from_secs(self.history_settings.ttl);
+        // Drop expired channels as we go, as nothing else will.
+        if history
+            .last_pruned
+            .is_none_or(|t| now.duration_since(t) > PRUNE_INTERVAL)
+        {
+            history.channels.retain(|_, h| h.expires > now);
+            history.last_pruned = Some(now);
+        }
+        let channel = history
+            .channels
+            .entry(channel_id.to_owned())
+            .or_insert_with(|| ChannelHistory {
+                messages: VecDeque::new(),
+                expires: now + ttl,
+            });
+        channel.expires = now + ttl;
+        channel.messages.push_back(message.clone());
+        while channel.messages.len() > self.history_settings.max_length {
+            channel.messages.pop_front();
+        }
+    }
+}
+
+#[async_trait]
+impl Broker for MemoryBroker {
+    async fn publish(&self, channel_id: &str, mut message: Message) -> anyhow::Result<Published> {
+        if self.history_settings.enabled_for(channel_id) {
+            self.append_history(channel_id, &mut message);
+        }
+        let id = message.id;
+        let tagged = (channel_id.to_owned(), message.clone());
+        let receivers = self.fanout.send(channel_id, message)
+            + self.patterns.send_matching(
+                |pattern| {
+                    pattern
+                        .parse::<ChannelPattern>()
+                        .is_ok_and(|pattern| pattern.matches(channel_id))
+                },
+                tagged,
+            );
+        Ok(Published {
+            id,
+            receivers: Some(receivers),
+        })
+    }
+
+    async fn subscribe(&self, channel_id: &str) -> anyhow::Result<MessageStream> {
+        Ok(self.fanout.subscribe(channel_id).boxed())
+    }
+
+    async fn psubscribe(&self, pattern: &ChannelPattern) -> anyhow::Result<PatternStream> {
+        Ok(self.patterns.subscribe(&pattern.to_string()).boxed())
+    }
+
+    async fn history(&self, channel_id: &str, start: HistoryStart) -> anyhow::Result<Vec<Message>> {
+        let history = self.history.lock();
+        let messages = match history.channels.get(channel_id) {
+            Some(channel) if channel.expires > Instant::now() => channel
+                .messages
+                .iter()
+                .filter(|m| m.id.map(|id| start.includes(&id)).unwrap_or_default())
+                .cloned()
+                .collect(),
+            _ => vec![],
+        };
+        Ok(messages)
+    }
+
+    async fn stats(&self, channel_id: &str) -> anyhow::Result<ChannelStats> {
+        Ok(ChannelStats {
+            subscribers: self.fanout.subscribers(channel_id),
+            nodes: None,
+        })
+    }
+}
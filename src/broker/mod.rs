@@ -0,0 +1,271 @@
+use crate::error::RequestError;
+use crate::settings::{BrokerBackend, Settings};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+mod fanout;
+mod memory;
+mod nats;
+mod postgres;
+mod redis;
+
+pub use self::memory::MemoryBroker;
+pub use self::nats::NatsBroker;
+pub use self::postgres::PostgresBroker;
+pub use self::redis::RedisBroker;
+
+/// A stream of messages delivered to a single channel subscriber.
+pub type MessageStream = BoxStream<'static, anyhow::Result<Message>>;
+/// A stream of messages from every channel matching a pattern, with their channel IDs.
+pub type PatternStream = BoxStream<'static, anyhow::Result<(String, Message)>>;
+
+/// Identifies a message kept in a channel's history.
+///
+/// Uses the Redis stream ID format, `<unix millis>-<sequence>`, so IDs sort by publish time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MessageId {
+    pub millis: u64,
+    pub seq: u64,
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.millis, self.seq)
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (millis, seq) = s.split_once('-').unwrap_or((s, "0"));
+        Ok(Self {
+            millis: millis.parse()?,
+            seq: seq.parse()?,
+        })
+    }
+}
+
+/// Matches every channel in a namespace, written `{prefix}*` where the prefix ends with `:`.
+///
+/// The prefix can't contain wildcards, so a pattern only ever matches within its namespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelPattern {
+    prefix: String,
+}
+
+impl ChannelPattern {
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn matches(&self, channel_id: &str) -> bool {
+        channel_id.starts_with(&self.prefix)
+    }
+
+    /// Whether every channel this matches is also matched by `other`.
+    pub fn within(&self, other: &ChannelPattern) -> bool {
+        self.prefix.starts_with(&other.prefix)
+    }
+}
+
+impl fmt::Display for ChannelPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}*", self.prefix)
+    }
+}
+
+impl FromStr for ChannelPattern {
+    type Err = RequestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| RequestError::InvalidParameter {
+            name: "channel_pattern",
+            reason: reason.to_owned(),
+        };
+        let prefix = s
+            .strip_suffix('*')
+            .ok_or_else(|| invalid("Patterns must end with `*`"))?;
+        if prefix.len() < 2 || !prefix.ends_with(':') {
+            return Err(invalid("Patterns must be a namespace followed by `:*`"));
+        }
+        if prefix.contains(['*', '?', '[', ']', '\\']) {
+            return Err(invalid("Patterns may only have a single trailing `*`"));
+        }
+        Ok(Self {
+            prefix: prefix.to_owned(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    /// Set for messages kept in the channel's history.
+    pub id: Option<MessageId>,
+    /// The publisher's `Content-Type`, for brokers that carry it.
+    pub content_type: Option<String>,
+    /// Names the kind of message, for clients of the JSON envelope.
+    pub event: Option<String>,
+    /// When the message was published, in unix milliseconds.
+    pub timestamp: Option<u64>,
+    /// Headers the publisher attached to the message.
+    pub headers: BTreeMap<String, String>,
+    pub payload: Bytes,
+}
+
+impl Message {
+    pub fn new(payload: Bytes) -> Self {
+        Self {
+            payload,
+            ..Default::default()
+        }
+    }
+}
+
+/// Message metadata, as brokers without their own headers carry it beside the payload.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+struct Header {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ts: Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+}
+
+impl Header {
+    fn new(message: &Message) -> Self {
+        Self {
+            id: message.id.map(|id| id.to_string()),
+            content_type: message.content_type.clone(),
+            event: message.event.clone(),
+            ts: message.timestamp,
+            headers: message.headers.clone(),
+        }
+    }
+
+    fn into_message(self, payload: Bytes) -> Message {
+        Message {
+            id: self.id.and_then(|id| id.parse().ok()),
+            content_type: self.content_type,
+            event: self.event,
+            timestamp: self.ts,
+            headers: self.headers,
+            payload,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Published {
+    pub id: Option<MessageId>,
+    /// How many subscribers received the message, when the broker can tell.
+    ///
+    /// Brokers shared between nodes count each subscribed node once.
+    pub receivers: Option<usize>,
+}
+
+/// Where to start replaying a channel's history from.
+#[derive(Debug, Clone, Copy)]
+pub enum HistoryStart {
+    /// Messages published after the one with this ID.
+    After(MessageId),
+    /// Messages published at or after this time, in unix milliseconds.
+    Since(u64),
+}
+
+impl HistoryStart {
+    fn includes(&self, id: &MessageId) -> bool {
+        match self {
+            HistoryStart::After(after) => id > after,
+            HistoryStart::Since(millis) => id.millis >= *millis,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Clone)]
+pub struct ChannelStats {
+    /// Subscribers connected to this node.
+    pub subscribers: usize,
+    /// Nodes subscribed to the channel, for brokers shared between nodes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<usize>,
+}
+
+/// Relays published channel messages to subscribers.
+#[async_trait]
+pub trait Broker: Send + Sync {
+    async fn publish(&self, channel_id: &str, message: Message) -> anyhow::Result<Published>;
+
+    /// Whether `Published::receivers` is set.
+    fn counts_receivers(&self) -> bool {
+        true
+    }
+
+    async fn subscribe(&self, channel_id: &str) -> anyhow::Result<MessageStream>;
+
+    /// Subscribes to every channel matching the pattern, for brokers that support it.
+    async fn psubscribe(&self, _pattern: &ChannelPattern) -> anyhow::Result<PatternStream> {
+        Err(RequestError::InvalidParameter {
+            name: "channel_pattern",
+            reason: "The broker doesn't support pattern subscriptions".to_owned(),
+        }
+        .into())
+    }
+
+    /// Messages kept in the channel's history, oldest first.
+    ///
+    /// Channels without history enabled return nothing.
+    async fn history(&self, channel_id: &str, start: HistoryStart) -> anyhow::Result<Vec<Message>>;
+
+    async fn stats(&self, channel_id: &str) -> anyhow::Result<ChannelStats>;
+
+    /// Subscribes to a channel, first replaying any history from `start`.
+    async fn resume(
+        &self,
+        channel_id: &str,
+        start: Option<HistoryStart>,
+    ) -> anyhow::Result<MessageStream> {
+        // Subscribe before reading history, so nothing is missed in between.
+        let live = self.subscribe(channel_id).await?;
+        let start = match start {
+            Some(start) => start,
+            None => return Ok(live),
+        };
+
+        let history = self.history(channel_id, start).await?;
+        let last_id = history.iter().rev().find_map(|m| m.id);
+        // Messages published while reading history can arrive twice, skip those already replayed.
+        let live = live.filter(move |result| {
+            let replayed = match (result, last_id) {
+                (Ok(Message { id: Some(id), .. }), Some(last_id)) => *id <= last_id,
+                _ => false,
+            };
+            futures::future::ready(!replayed)
+        });
+        Ok(stream::iter(history.into_iter().map(Ok))
+            .chain(live)
+            .boxed())
+    }
+}
+
+pub async fn from_settings(settings: &Settings) -> anyhow::Result<Arc<dyn Broker>> {
+    let broker: Arc<dyn Broker> = match settings.broker.backend {
+        BrokerBackend::Redis => Arc::new(RedisBroker::new(&settings.redis, &settings.history)?),
+        BrokerBackend::Memory => Arc::new(MemoryBroker::new(&settings.memory, &settings.history)),
+        BrokerBackend::Nats => Arc::new(NatsBroker::new(&settings.nats).await?),
+        BrokerBackend::Postgres => Arc::new(PostgresBroker::new(&settings.postgres)?),
+    };
+    Ok(broker)
+}
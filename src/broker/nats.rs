@@ -0,0 +1,217 @@
+use super::{
+    fanout::Fanout, Broker, ChannelStats, HistoryStart, Message, MessageStream, Published,
+};
+use crate::{error::RequestError, settings};
+use anyhow::Context;
+use async_nats::HeaderMap;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// NATS header carrying the publisher's content type.
+const CONTENT_TYPE: &str = "Content-Type";
+const EVENT_HEADER: &str = "Webchannel-Event";
+const TIMESTAMP_HEADER: &str = "Webchannel-Timestamp";
+/// Prefixes the headers publishers attached to a message.
+const HEADER_PREFIX: &str = "Webchannel-Header-";
+
+/// Maps message metadata to NATS headers, so other NATS clients can read it too.
+fn encode_headers(message: &Message) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(content_type) = &message.content_type {
+        headers.insert(CONTENT_TYPE, content_type.as_str());
+    }
+    if let Some(event) = &message.event {
+        headers.insert(EVENT_HEADER, event.as_str());
+    }
+    if let Some(timestamp) = message.timestamp {
+        headers.insert(TIMESTAMP_HEADER, timestamp.to_string().as_str());
+    }
+    for (name, value) in &message.headers {
+        headers.insert(
+            format!("{}{}", HEADER_PREFIX, name).as_str(),
+            value.as_str(),
+        );
+    }
+    headers
+}
+
+fn decode_headers(headers: &HeaderMap, payload: Bytes) -> Message {
+    let get = |name: &str| headers.get(name).map(|value| value.to_string());
+    let mut message = Message::new(payload);
+    message.content_type = get(CONTENT_TYPE);
+    message.event = get(EVENT_HEADER);
+    message.timestamp = get(TIMESTAMP_HEADER).and_then(|ts| ts.parse().ok());
+    for (name, values) in headers.iter() {
+        let name = name.to_string();
+        if let (Some(name), Some(value)) = (name.strip_prefix(HEADER_PREFIX), values.first()) {
+            message
+                .headers
+                .insert(name.to_ascii_lowercase(), value.to_string());
+        }
+    }
+    message
+}
+
+/// Maps a channel to its subject, `{prefix}.{channel_id}`.
+///
+/// Channel IDs may contain dots, adding subject tokens, but not wildcards or whitespace.
+fn make_subject(prefix: &str, channel_id: &str) -> Result<String, RequestError> {
+    let invalid = |reason: &str| RequestError::InvalidParameter {
+        name: "channel_id",
+        reason: reason.to_owned(),
+    };
+    if channel_id.split('.').any(|token| token.is_empty()) {
+        return Err(invalid("NATS subject tokens must not be empty"));
+    }
+    if channel_id
+        .chars()
+        .any(|c| c == '*' || c == '>' || c.is_whitespace())
+    {
+        return Err(invalid(
+            "NATS subjects must not contain wildcards or whitespace",
+        ));
+    }
+    Ok(format!("{}.{}", prefix, channel_id))
+}
+
+type Tasks = Arc<Mutex<HashMap<String, JoinHandle<()>>>>;
+
+/// Relays channels through NATS subjects, so services can publish to them directly.
+///
+/// Each channel is subscribed to once per node, and fanned out locally.
+pub struct NatsBroker {
+    client: async_nats::Client,
+    subject_prefix: String,
+    fanout: Fanout,
+    /// Tasks forwarding each subscribed subject to the fan-out.
+    tasks: Tasks,
+}
+
+impl NatsBroker {
+    pub async fn new(settings: &settings::Nats) -> anyhow::Result<Self> {
+        let mut options = async_nats::ConnectOptions::new()
+            .name("webchannel")
+            .retry_on_initial_connect();
+        if let Some(path) = &settings.credentials_file {
+            options = options
+                .credentials_file(path)
+                .await
+                .with_context(|| format!("Failed to read NATS credentials {:?}", path))?;
+        }
+        let servers = settings
+            .servers
+            .iter()
+            .map(|server| server.parse())
+            .collect::<Result<Vec<async_nats::ServerAddr>, _>>()
+            .context("Invalid nats.servers")?;
+        let client = async_nats::connect_with_options(servers, options)
+            .await
+            .context("Failed to connect to NATS")?;
+
+        let tasks: Tasks = Default::default();
+        let fanout = {
+            let tasks = tasks.clone();
+            Fanout::new(settings.channel_capacity).with_on_empty(move |channel_id| {
+                // Dropping the subscriber unsubscribes from the subject.
+                if let Some(task) = tasks.lock().remove(channel_id) {
+                    task.abort();
+                }
+            })
+        };
+
+        Ok(Self {
+            client,
+            subject_prefix: settings.subject_prefix.clone(),
+            fanout,
+            tasks,
+        })
+    }
+}
+
+#[async_trait]
+impl Broker for NatsBroker {
+    async fn publish(&self, channel_id: &str, message: Message) -> anyhow::Result<Published> {
+        let subject = make_subject(&self.subject_prefix, channel_id)?;
+        let headers = encode_headers(&message);
+        let published = if headers.is_empty() {
+            self.client.publish(subject, message.payload).await
+        } else {
+            self.client
+                .publish_with_headers(subject, headers, message.payload)
+                .await
+        };
+        published.context("Failed to publish to NATS")?;
+        self.client
+            .flush()
+            .await
+            .context("Failed to flush NATS connection")?;
+        Ok(Published::default())
+    }
+
+    fn counts_receivers(&self) -> bool {
+        false
+    }
+
+    async fn subscribe(&self, channel_id: &str) -> anyhow::Result<MessageStream> {
+        let subject = make_subject(&self.subject_prefix, channel_id)?;
+        // Our own subscription keeps the channel from emptying while the subject is subscribed.
+        let subscription = self.fanout.subscribe(channel_id);
+
+        if !self.tasks.lock().contains_key(channel_id) {
+            let mut subscriber = self
+                .client
+                .subscribe(subject)
+                .await
+                .context("Failed subscribing to NATS subject")?;
+            let mut tasks = self.tasks.lock();
+            // Another subscriber may have won the race, then this one is dropped again.
+            if !tasks.contains_key(channel_id) {
+                let key = channel_id.to_owned();
+                let fanout = self.fanout.clone();
+                let forwarder_tasks = self.tasks.clone();
+                let channel_id = key.clone();
+                let task = tokio::spawn(async move {
+                    while let Some(message) = subscriber.next().await {
+                        let decoded = match &message.headers {
+                            Some(headers) => decode_headers(headers, message.payload),
+                            None => Message::new(message.payload),
+                        };
+                        fanout.send(&channel_id, decoded);
+                    }
+                    debug!("NATS subscription to {:?} ended", channel_id);
+                    forwarder_tasks.lock().remove(&channel_id);
+                    fanout.close(&channel_id);
+                });
+                tasks.insert(key, task);
+            }
+        }
+
+        // Make sure the server has the subscription before reporting it ready.
+        self.client
+            .flush()
+            .await
+            .context("Failed to flush NATS connection")?;
+        Ok(subscription.boxed())
+    }
+
+    async fn history(
+        &self,
+        _channel_id: &str,
+        _start: HistoryStart,
+    ) -> anyhow::Result<Vec<Message>> {
+        Ok(vec![])
+    }
+
+    async fn stats(&self, channel_id: &str) -> anyhow::Result<ChannelStats> {
+        Ok(ChannelStats {
+            subscribers: self.fanout.subscribers(channel_id),
+            nodes: None,
+        })
+    }
+}
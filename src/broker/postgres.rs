@@ -0,0 +1,320 @@
+use super::{
+    fanout::Fanout, Broker, ChannelStats, Header, HistoryStart, Message, MessageStream, Published,
+};
+use crate::{error::RequestError, settings};
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_postgres::{config::SslMode, AsyncMessage, Client, NoTls};
+use tracing::{debug, error, info};
+
+/// Longest identifier Postgres keeps, longer ones are silently truncated.
+const MAX_IDENTIFIER_LEN: usize = 63;
+/// NOTIFY payloads must be shorter than 8000 bytes.
+const MAX_PAYLOAD_LEN: usize = 7999;
+/// Starts notifications carrying metadata, as a line of JSON ahead of the payload.
+const HEADER_MARKER: char = '\u{1}';
+
+/// Maps a channel to its Postgres channel name, `{prefix}{channel_id}`.
+fn make_channel_name(prefix: &str, channel_id: &str) -> Result<String, RequestError> {
+    let name = format!("{}{}", prefix, channel_id);
+    if name.len() > MAX_IDENTIFIER_LEN {
+        return Err(RequestError::InvalidParameter {
+            name: "channel_id",
+            reason: format!(
+                "Postgres channel names are limited to {} bytes, including the prefix {:?}",
+                MAX_IDENTIFIER_LEN, prefix
+            ),
+        });
+    }
+    if name.contains('\0') {
+        return Err(RequestError::InvalidParameter {
+            name: "channel_id",
+            reason: "Postgres channel names must not contain NUL".to_owned(),
+        });
+    }
+    Ok(name)
+}
+
+/// Encodes a message as a notification payload, with its metadata on a first line if any.
+fn encode_notification(message: &Message) -> Result<String, RequestError> {
+    let payload = std::str::from_utf8(&message.payload)
+        .ok()
+        .filter(|payload| !payload.contains('\0'))
+        .ok_or_else(|| RequestError::InvalidParameter {
+            name: "payload",
+            reason: "Postgres notifications must be UTF-8 text".to_owned(),
+        })?;
+    let header = Header::new(message);
+    let mut notification = String::new();
+    if header != Header::default() {
+        notification.push(HEADER_MARKER);
+        // JSON escapes newlines, so the header ends at the first.
+        notification.push_str(&serde_json::to_string(&header).expect("Failed to serialize header"));
+        notification.push('\n');
+    }
+    let header_len = notification.len();
+    if header_len + payload.len() > MAX_PAYLOAD_LEN {
+        return Err(RequestError::PayloadTooLarge {
+            limit: MAX_PAYLOAD_LEN.saturating_sub(header_len),
+        });
+    }
+    notification.push_str(payload);
+    Ok(notification)
+}
+
+/// Decodes a notification payload, which is taken as it is without a metadata line.
+fn decode_notification(notification: &str) -> Message {
+    let framed = notification
+        .strip_prefix(HEADER_MARKER)
+        .and_then(|framed| framed.split_once('\n'))
+        .and_then(|(header, payload)| {
+            Some((serde_json::from_str::<Header>(header).ok()?, payload))
+        });
+    match framed {
+        Some((header, payload)) => header.into_message(Bytes::copy_from_slice(payload.as_bytes())),
+        None => Message::new(Bytes::copy_from_slice(notification.as_bytes())),
+    }
+}
+
+/// Quotes a channel name for LISTEN, which doesn't take bind parameters.
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// The shared connection, and the channels it listens to.
+struct Session {
+    client: Arc<Client>,
+    listening: HashSet<String>,
+}
+
+type SharedSession = Arc<Mutex<Option<Session>>>;
+
+/// Relays channels through Postgres LISTEN/NOTIFY, for deployments without Redis.
+///
+/// Each channel is listened to once per node on a shared connection, and fanned out locally.
+/// Payloads must be UTF-8 text, as NOTIFY doesn't carry binary data. Metadata goes on a first
+/// line, marked with `\u{1}`, as NOTIFY doesn't carry headers either.
+pub struct PostgresBroker {
+    url: String,
+    channel_prefix: String,
+    fanout: Fanout,
+    session: SharedSession,
+}
+
+impl PostgresBroker {
+    pub fn new(settings: &settings::Postgres) -> anyhow::Result<Self> {
+        let config = settings
+            .url
+            .parse::<tokio_postgres::Config>()
+            .context("Invalid postgres.url")?;
+        // Connections are made without TLS, which `prefer` falls back to.
+        if config.get_ssl_mode() == SslMode::Require {
+            return Err(anyhow::anyhow!(
+                "TLS isn't supported by the Postgres backend, remove sslmode=require"
+            ));
+        }
+
+        let session: SharedSession = Default::default();
+        let fanout = Fanout::new(settings.channel_capacity);
+        let fanout = {
+            let channels = fanout.clone();
+            let session = session.clone();
+            let prefix = settings.channel_prefix.clone();
+            fanout.with_on_empty(move |channel_id| {
+                tokio::spawn(unlisten(
+                    session.clone(),
+                    channels.clone(),
+                    prefix.clone(),
+                    channel_id.to_owned(),
+                ));
+            })
+        };
+
+        Ok(Self {
+            url: settings.url.clone(),
+            channel_prefix: settings.channel_prefix.clone(),
+            fanout,
+            session,
+        })
+    }
+
+    /// Returns the current session, connecting again if the connection was lost.
+    async fn connect<'a>(&self, session: &'a mut Option<Session>) -> anyhow::Result<&'a Session> {
+        close_lost(session, &self.fanout, &self.channel_prefix);
+        if session.is_none() {
+            let (client, connection) = tokio_postgres::connect(&self.url, NoTls)
+                .await
+                .context("Failed to connect to postgres")?;
+            info!("Connected to postgres");
+            tokio::spawn(forward_notifications(
+                connection,
+                self.session.clone(),
+                self.fanout.clone(),
+                self.channel_prefix.clone(),
+            ));
+            *session = Some(Session {
+                client: Arc::new(client),
+                listening: HashSet::new(),
+            });
+        }
+        Ok(session.as_ref().unwrap())
+    }
+}
+
+/// Drops a lost session, ending the subscriptions that relied on it.
+fn close_lost(session: &mut Option<Session>, fanout: &Fanout, prefix: &str) {
+    if let Some(lost) = session.take_if(|session| session.client.is_closed()) {
+        for name in &lost.listening {
+            if let Some(channel_id) = name.strip_prefix(prefix) {
+                fanout.close(channel_id);
+            }
+        }
+    }
+}
+
+async fn forward_notifications<S, T>(
+    mut connection: tokio_postgres::Connection<S, T>,
+    session: SharedSession,
+    fanout: Fanout,
+    prefix: String,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+    while let Some(message) = messages.next().await {
+        match message {
+            Ok(AsyncMessage::Notification(notification)) => {
+                if let Some(channel_id) = notification.channel().strip_prefix(&prefix) {
+                    fanout.send(channel_id, decode_notification(notification.payload()));
+                }
+            }
+            Ok(message) => debug!("Postgres message: {:?}", message),
+            Err(e) => {
+                error!("Postgres connection error: {:?}", e);
+                break;
+            }
+        }
+    }
+    close_lost(&mut *session.lock().await, &fanout, &prefix);
+}
+
+/// Stops listening to a channel, unless it gained a subscriber in the meantime.
+async fn unlisten(session: SharedSession, fanout: Fanout, prefix: String, channel_id: String) {
+    let mut session = session.lock().await;
+    let session = match session.as_mut() {
+        Some(session) => session,
+        None => return,
+    };
+    let name = format!("{}{}", prefix, channel_id);
+    if fanout.subscribers(&channel_id) > 0 || !session.listening.remove(&name) {
+        return;
+    }
+    let unlisten = format!("UNLISTEN {}", quote_identifier(&name));
+    if let Err(e) = session.client.batch_execute(&unlisten).await {
+        debug!("Failed to unlisten from {:?}: {:?}", name, e);
+    }
+}
+
+#[async_trait]
+impl Broker for PostgresBroker {
+    async fn publish(&self, channel_id: &str, message: Message) -> anyhow::Result<Published> {
+        let name = make_channel_name(&self.channel_prefix, channel_id)?;
+        let payload = encode_notification(&message)?;
+
+        let client = {
+            let mut session = self.session.lock().await;
+            self.connect(&mut session).await?.client.clone()
+        };
+        client
+            .execute("SELECT pg_notify($1, $2)", &[&name, &payload])
+            .await
+            .context("Failed to notify postgres channel")?;
+        Ok(Published::default())
+    }
+
+    fn counts_receivers(&self) -> bool {
+        false
+    }
+
+    async fn subscribe(&self, channel_id: &str) -> anyhow::Result<MessageStream> {
+        let name = make_channel_name(&self.channel_prefix, channel_id)?;
+        let mut session = self.session.lock().await;
+        self.connect(&mut session).await?;
+        let session = session.as_mut().unwrap();
+        // Subscribe while holding the session, so a pending unlisten sees the new subscriber.
+        let subscription = self.fanout.subscribe(channel_id);
+        if !session.listening.contains(&name) {
+            session
+                .client
+                .batch_execute(&format!("LISTEN {}", quote_identifier(&name)))
+                .await
+                .context("Failed to listen to postgres channel")?;
+            session.listening.insert(name);
+        }
+        Ok(subscription.boxed())
+    }
+
+    async fn history(
+        &self,
+        _channel_id: &str,
+        _start: HistoryStart,
+    ) -> anyhow::Result<Vec<Message>> {
+        Ok(vec![])
+    }
+
+    async fn stats(&self, channel_id: &str) -> anyhow::Result<ChannelStats> {
+        Ok(ChannelStats {
+            subscribers: self.fanout.subscribers(channel_id),
+            nodes: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carries_metadata_in_notifications() {
+        let message = Message {
+            content_type: Some("application/json".to_owned()),
+            event: Some("created".to_owned()),
+            timestamp: Some(1),
+            ..Message::new(Bytes::from_static(b"{\"id\":1}\nmore"))
+        };
+        let notification = encode_notification(&message).unwrap();
+        assert!(notification.starts_with(HEADER_MARKER));
+        let decoded = decode_notification(&notification);
+        assert_eq!(decoded.content_type, message.content_type);
+        assert_eq!(decoded.event, message.event);
+        assert_eq!(decoded.timestamp, Some(1));
+        assert_eq!(decoded.payload, message.payload);
+    }
+
+    #[test]
+    fn passes_plain_notifications_through() {
+        let message = Message::new(Bytes::from_static(b"hello"));
+        assert_eq!(encode_notification(&message).unwrap(), "hello");
+        assert_eq!(decode_notification("hello").payload, "hello");
+        // Notifications from elsewhere that merely look framed are left alone.
+        assert_eq!(decode_notification("\u{1}nope\nx").payload, "\u{1}nope\nx");
+    }
+
+    #[test]
+    fn counts_metadata_against_the_payload_limit() {
+        let message = Message {
+            event: Some("created".to_owned()),
+            ..Message::new(Bytes::from("x".repeat(MAX_PAYLOAD_LEN)))
+        };
+        assert!(matches!(
+            encode_notification(&message),
+            Err(RequestError::PayloadTooLarge { .. })
+        ));
+    }
+}
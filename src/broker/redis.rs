@@ -0,0 +1,517 @@
+use super::{
+    fanout::Fanout, Broker, ChannelPattern, ChannelStats, Header, HistoryStart, Message,
+    MessageStream, PatternStream, Published,
+};
+use crate::{
+    cluster::{key_slot, Cluster},
+    connector::Connector,
+    error::RequestError,
+    metrics,
+    pool::{self, Pool},
+    pubsub::{Event, Pubsub},
+    settings,
+};
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use parking_lot::Mutex;
+use redis_async::error::Error as RedisError;
+use redis_async::{
+    resp::{FromResp, RespValue},
+    resp_array,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+fn make_channel_key(channel_id: &str) -> String {
+    format!("wc:channel:{}", channel_id)
+}
+
+fn channel_id_from_key(key: &str) -> Option<&str> {
+    key.strip_prefix("wc:channel:")
+}
+
+fn make_history_key(channel_id: &str) -> String {
+    format!("wc:history:{}", channel_id)
+}
+
+/// The key carrying the metadata of messages published on a channel key, or pattern of keys.
+///
+/// It's in the channel key's hash slot, so a cluster node takes both in one transaction and they
+/// share a pub/sub connection. Channel keys whose own braces rule that out go without metadata.
+fn make_meta_key(key: &str) -> Option<String> {
+    let slot = key_slot(key.as_bytes());
+    vec![format!("wc:meta:{{{}}}", key), format!("wc:meta:{}", key)]
+        .into_iter()
+        .find(|meta| key_slot(meta.as_bytes()) == slot)
+}
+
+fn is_meta_key(key: &str) -> bool {
+    key.starts_with("wc:meta:")
+}
+
+/// Metadata received on meta keys, held until the message it belongs to follows.
+///
+/// Metadata is published just before its message in one transaction, so a connection subscribed
+/// to both keys receives the pair back to back. Pattern subscriptions are held apart, as both a
+/// key and a pattern matching it may be subscribed to.
+#[derive(Clone, Default)]
+struct PendingMetadata(Arc<Mutex<HashMap<MetaSource, Bytes>>>);
+
+/// The pattern, if any, and meta key that metadata was received on.
+type MetaSource = (Option<String>, String);
+
+impl PendingMetadata {
+    fn hold(&self, pattern: Option<String>, key: String, header: Bytes) {
+        self.0.lock().insert((pattern, key), header);
+    }
+
+    /// Builds a message on `key`, with the metadata held for it if any.
+    fn message(&self, pattern: Option<&str>, key: &str, payload: Bytes) -> Message {
+        let meta_pattern = match pattern {
+            Some(pattern) => make_meta_key(pattern).map(Some),
+            None => Some(None),
+        };
+        let header = meta_pattern
+            .zip(make_meta_key(key))
+            .and_then(|meta| self.0.lock().remove(&meta))
+            .and_then(|header| serde_json::from_slice::<Header>(&header).ok());
+        header.unwrap_or_default().into_message(payload)
+    }
+
+    /// Drops metadata held for subscriptions lost with a connection.
+    fn forget(&self, keys: &[String], patterns: &[String]) {
+        self.0.lock().retain(|(pattern, key), _| match pattern {
+            Some(pattern) => !patterns.contains(pattern),
+            None => !keys.contains(key),
+        });
+    }
+}
+
+/// Sends commands and pub/sub subscriptions to a single server, or across a cluster.
+#[derive(Clone)]
+enum Client {
+    Single { pool: Pool, pubsub: Pubsub },
+    Cluster(Arc<Cluster>),
+}
+
+impl Client {
+    fn is_cluster(&self) -> bool {
+        matches!(self, Client::Cluster(_))
+    }
+
+    /// Sends a command about `key`, which picks the node in a cluster.
+    async fn send<T>(&self, key: &str, command: RespValue) -> anyhow::Result<T>
+    where
+        T: FromResp + Unpin,
+    {
+        match self {
+            Client::Single { pool, .. } => {
+                let connection = pool
+                    .get()
+                    .await
+                    .context("Failed to get redis connection from pool")?;
+                Ok(connection.send(command).await?)
+            }
+            Client::Cluster(cluster) => cluster.send(key, command).await,
+        }
+    }
+
+    /// Sends commands about `key` in a row on one connection, returning their replies with any
+    /// errors among them.
+    async fn send_all(
+        &self,
+        key: &str,
+        commands: Vec<RespValue>,
+    ) -> anyhow::Result<Vec<RespValue>> {
+        match self {
+            Client::Single { pool, .. } => {
+                let connection = pool
+                    .get()
+                    .await
+                    .context("Failed to get redis connection from pool")?;
+                let sent: Vec<_> = commands
+                    .into_iter()
+                    .map(|command| connection.send::<RespValue>(command))
+                    .collect();
+                let mut replies = vec![];
+                for reply in sent {
+                    replies.push(match reply.await {
+                        Err(RedisError::Remote(reply)) => RespValue::Error(reply),
+                        reply => reply?,
+                    });
+                }
+                Ok(replies)
+            }
+            Client::Cluster(cluster) => cluster.send_all(key, commands).await,
+        }
+    }
+
+    /// Subscribes to a channel key and its meta key, the meta key first so metadata isn't
+    /// missed for messages received.
+    async fn subscribe(&self, key: &str) -> anyhow::Result<()> {
+        for key in make_meta_key(key).iter().map(String::as_str).chain([key]) {
+            match self {
+                Client::Single { pubsub, .. } => pubsub.subscribe(key).await?,
+                Client::Cluster(cluster) => cluster.subscribe(key).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Unsubscribes from a channel key and its meta key, the meta key first so no metadata is
+    /// left waiting for a message.
+    fn unsubscribe(&self, key: &str) {
+        for key in make_meta_key(key).iter().map(String::as_str).chain([key]) {
+            match self {
+                Client::Single { pubsub, .. } => pubsub.unsubscribe(key),
+                Client::Cluster(cluster) => cluster.unsubscribe(key),
+            }
+        }
+    }
+}
+
+pub struct RedisBroker {
+    client: Client,
+    fanout: Fanout,
+    /// Pattern subscriptions, keyed by pattern.
+    patterns: Fanout<(String, Message)>,
+    history: settings::History,
+}
+
+impl RedisBroker {
+    pub fn new(settings: &settings::Redis, history: &settings::History) -> anyhow::Result<Self> {
+        let fanout = Fanout::new(settings.channel_capacity);
+        let patterns: Fanout<(String, Message)> = Fanout::new(settings.channel_capacity);
+        let on_event = {
+            let fanout = fanout.clone();
+            let patterns = patterns.clone();
+            let metadata = PendingMetadata::default();
+            move |event| match event {
+                Event::Message { key, payload } if is_meta_key(&key) => {
+                    metadata.hold(None, key, payload);
+                }
+                Event::Message { key, payload } => {
+                    if let Some(channel_id) = channel_id_from_key(&key) {
+                        fanout.send(channel_id, metadata.message(None, &key, payload));
+                    }
+                }
+                Event::PatternMessage {
+                    pattern,
+                    key,
+                    payload,
+                } if is_meta_key(&pattern) => {
+                    metadata.hold(Some(pattern), key, payload);
+                }
+                Event::PatternMessage {
+                    pattern,
+                    key,
+                    payload,
+                } => {
+                    if let (Some(channel_pattern), Some(channel_id)) =
+                        (channel_id_from_key(&pattern), channel_id_from_key(&key))
+                    {
+                        let message = metadata.message(Some(&pattern), &key, payload);
+                        patterns.send(channel_pattern, (channel_id.to_owned(), message));
+                    }
+                }
+                Event::PatternUnsubscribed { pattern } => {
+                    if let Some(pattern) = channel_id_from_key(&pattern) {
+                        patterns.close(pattern);
+                    }
+                }
+                Event::Unsubscribed { key } => {
+                    if let Some(channel_id) = channel_id_from_key(&key) {
+                        fanout.close(channel_id);
+                    }
+                }
+                Event::Disconnected {
+                    keys,
+                    patterns: lost,
+                } => {
+                    metadata.forget(&keys, &lost);
+                    for channel_id in keys.iter().filter_map(|key| channel_id_from_key(key)) {
+                        fanout.close(channel_id);
+                    }
+                    for pattern in lost.iter().filter_map(|key| channel_id_from_key(key)) {
+                        patterns.close(pattern);
+                    }
+                }
+            }
+        };
+
+        let connector = Connector::new(settings)?;
+        let client = match &settings.cluster {
+            Some(_) if settings.sentinel.is_some() => {
+                return Err(anyhow::anyhow!(
+                    "Redis sentinel and cluster can't be configured together"
+                ))
+            }
+            Some(_) if connector.database().unwrap_or_default() != 0 => {
+                return Err(anyhow::anyhow!("Redis cluster only supports database 0"))
+            }
+            Some(cluster) => Client::Cluster(Arc::new(Cluster::new(
+                connector, settings, cluster, on_event,
+            ))),
+            None => {
+                let pool_mgr = pool::Manager::new(connector.clone());
+                Client::Single {
+                    pool: Pool::new(pool_mgr, settings.pool_size),
+                    pubsub: Pubsub::new(connector, settings.pubsub_connections, false, on_event),
+                }
+            }
+        };
+        let fanout = {
+            let client = client.clone();
+            fanout
+                .with_on_empty(move |channel_id| client.unsubscribe(&make_channel_key(channel_id)))
+        };
+
+        let patterns = match &client {
+            Client::Single { pubsub, .. } => {
+                let pubsub = pubsub.clone();
+                patterns.with_on_empty(move |pattern| {
+                    let pattern = make_channel_key(pattern);
+                    for pattern in make_meta_key(&pattern).iter().chain([&pattern]) {
+                        pubsub.punsubscribe(pattern);
+                    }
+                })
+            }
+            Client::Cluster(_) => patterns,
+        };
+
+        Ok(Self {
+            client,
+            fanout,
+            patterns,
+            history: history.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl Broker for RedisBroker {
+    async fn publish(&self, channel_id: &str, mut message: Message) -> anyhow::Result<Published> {
+        if self.history.enabled_for(channel_id) {
+            let key = make_history_key(channel_id);
+            let mut xadd = resp_array![
+                "XADD",
+                &key,
+                "MAXLEN",
+                "~",
+                self.history.max_length.to_string(),
+                "*",
+                "data",
+                message.payload.to_vec()
+            ];
+            // Metadata is kept as a JSON header field, beside the raw payload.
+            let header = Header::new(&message);
+            if let (RespValue::Array(args), false) = (&mut xadd, header == Header::default()) {
+                let header = serde_json::to_vec(&header).expect("Failed to serialize header");
+                args.extend([RespValue::from("header"), RespValue::from(header)]);
+            }
+            // EXPIRE follows on the same connection, so the stream exists when it runs.
+            let expire = resp_array!["EXPIRE", &key, self.history.ttl.to_string()];
+            let mut replies = self.client.send_all(&key, vec![xadd, expire]).await?;
+            let (expired, id) = (replies.pop(), replies.pop());
+            let id: String = FromResp::from_resp(id.context("Missing XADD reply")?)
+                .context("Failed to append message to history")?;
+            if let Some(RespValue::Error(e)) = expired {
+                return Err(anyhow::anyhow!("Failed to expire channel history: {}", e));
+            }
+            message.id = Some(id.parse()?);
+        }
+
+        // Sharded pub/sub keeps a cluster's messages within the shard owning the channel.
+        let command = if self.client.is_cluster() {
+            "SPUBLISH"
+        } else {
+            "PUBLISH"
+        };
+        let key = make_channel_key(channel_id);
+        let publish = resp_array![command, &key, message.payload.to_vec()];
+        let header = Header::new(&message);
+        let receivers = match make_meta_key(&key) {
+            // The payload goes out as published, for subscribers straight from Redis, and
+            // metadata just before it on the meta key.
+            Some(meta_key) if header != Header::default() => {
+                let header = serde_json::to_vec(&header).expect("Failed to serialize header");
+                let commands = vec![
+                    resp_array!["MULTI"],
+                    resp_array![command, meta_key, header],
+                    publish,
+                    resp_array!["EXEC"],
+                ];
+                self.client
+                    .send_all(&key, commands)
+                    .await
+                    .and_then(|mut replies| match replies.pop() {
+                        Some(RespValue::Array(counts)) if counts.len() == 2 => {
+                            Ok(usize::from_resp(counts[1].clone())?)
+                        }
+                        reply => Err(anyhow::anyhow!("Unexpected EXEC reply: {:?}", reply)),
+                    })
+            }
+            _ => self.client.send(&key, publish).await,
+        };
+        let receivers = receivers.map_err(|e| {
+            metrics::REDIS_PUBLISH_ERRORS.inc();
+            e.context("Failed to send publish command")
+        })?;
+        Ok(Published {
+            id: message.id,
+            receivers: Some(receivers),
+        })
+    }
+
+    async fn subscribe(&self, channel_id: &str) -> anyhow::Result<MessageStream> {
+        // Join the local fan-out first, so nothing published after the subscription is confirmed
+        // can be missed.
+        let subscription = self.fanout.subscribe(channel_id);
+        self.client
+            .subscribe(&make_channel_key(channel_id))
+            .await
+            .map_err(|e| {
+                metrics::REDIS_SUBSCRIBE_ERRORS.inc();
+                e.context("Failed subscribing to redis channel")
+            })?;
+        Ok(subscription.boxed())
+    }
+
+    async fn psubscribe(&self, pattern: &ChannelPattern) -> anyhow::Result<PatternStream> {
+        // Sharded pub/sub has no pattern subscriptions.
+        let pubsub = match &self.client {
+            Client::Single { pubsub, .. } => pubsub,
+            Client::Cluster(_) => {
+                return Err(RequestError::InvalidParameter {
+                    name: "channel_pattern",
+                    reason: "Pattern subscriptions aren't supported on a Redis cluster".to_owned(),
+                }
+                .into())
+            }
+        };
+        let pattern = pattern.to_string();
+        let subscription = self.patterns.subscribe(&pattern);
+        let key = make_channel_key(&pattern);
+        // The meta pattern first, as with channel keys.
+        for key in make_meta_key(&key).iter().chain([&key]) {
+            pubsub.psubscribe(key).await.map_err(|e| {
+                metrics::REDIS_SUBSCRIBE_ERRORS.inc();
+                e.context("Failed subscribing to redis channel pattern")
+            })?;
+        }
+        Ok(subscription.boxed())
+    }
+
+    async fn history(&self, channel_id: &str, start: HistoryStart) -> anyhow::Result<Vec<Message>> {
+        if !self.history.enabled_for(channel_id) {
+            return Ok(vec![]);
+        }
+
+        let range_start = match start {
+            HistoryStart::After(id) => id.to_string(),
+            HistoryStart::Since(millis) => format!("{}-0", millis),
+        };
+        let key = make_history_key(channel_id);
+        let resp = resp_array![
+            "XRANGE",
+            &key,
+            range_start,
+            "+",
+            "COUNT",
+            self.history.max_length.to_string()
+        ];
+        let entries: Vec<(String, Vec<Vec<u8>>)> = self
+            .client
+            .send(&key, resp)
+            .await
+            .context("Failed to read channel history")?;
+
+        let mut messages = vec![];
+        for (id, fields) in entries {
+            let id = id.parse()?;
+            if !start.includes(&id) {
+                continue;
+            }
+            let field = |name: &[u8]| {
+                fields
+                    .chunks(2)
+                    .find(|field| field[0] == name)
+                    .and_then(|field| field.get(1))
+                    .cloned()
+            };
+            let header: Header = field(b"header")
+                .and_then(|header| serde_json::from_slice(&header).ok())
+                .unwrap_or_default();
+            let mut message = header.into_message(field(b"data").unwrap_or_default().into());
+            message.id = Some(id);
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    async fn stats(&self, channel_id: &str) -> anyhow::Result<ChannelStats> {
+        let subcommand = if self.client.is_cluster() {
+            "SHARDNUMSUB"
+        } else {
+            "NUMSUB"
+        };
+        let key = make_channel_key(channel_id);
+        let resp = resp_array!["PUBSUB", subcommand, &key];
+        let (_channel, nodes): (String, i64) = self
+            .client
+            .send(&key, resp)
+            .await
+            .context("Failed to send numsub command")?;
+        Ok(ChannelStats {
+            subscribers: self.fanout.subscribers(channel_id),
+            nodes: Some(nodes as usize),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn makes_meta_keys_in_the_same_slot() {
+        assert_eq!(
+            make_meta_key("wc:channel:foo").as_deref(),
+            Some("wc:meta:{wc:channel:foo}")
+        );
+        assert_eq!(
+            make_meta_key("wc:channel:{user}:1").as_deref(),
+            Some("wc:meta:wc:channel:{user}:1")
+        );
+        assert_eq!(make_meta_key("wc:channel:a{}b}"), None);
+        assert_eq!(
+            make_meta_key("wc:channel:job:*").as_deref(),
+            Some("wc:meta:{wc:channel:job:*}")
+        );
+    }
+
+    #[test]
+    fn pairs_metadata_with_messages() {
+        let metadata = PendingMetadata::default();
+        let header = Bytes::from_static(br#"{"event":"progress"}"#);
+        metadata.hold(None, "wc:meta:{wc:channel:foo}".to_owned(), header.clone());
+        let message = metadata.message(None, "wc:channel:foo", "one".into());
+        assert_eq!(message.event.as_deref(), Some("progress"));
+        assert_eq!(message.payload, "one");
+        // Metadata goes with one message only.
+        let message = metadata.message(None, "wc:channel:foo", "two".into());
+        assert_eq!(message.event, None);
+
+        // Pattern subscriptions hold their own.
+        let pattern = "wc:meta:{wc:channel:job:*}".to_owned();
+        let key = "wc:meta:{wc:channel:job:1}".to_owned();
+        metadata.hold(Some(pattern.clone()), key.clone(), header);
+        let message = metadata.message(None, "wc:channel:job:1", "three".into());
+        assert_eq!(message.event, None);
+        metadata.forget(&[], &[pattern]);
+        let message = metadata.message(Some("wc:channel:job:*"), "wc:channel:job:1", "four".into());
+        assert_eq!(message.event, None);
+    }
+}
@@ -1,14 +1,118 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct CreateChannelRequest {
     #[serde(rename = "channelId")]
     pub channel_id: Option<String>,
+    /// Creates a token for every channel matching the pattern instead, e.g. `tenant:42:*`.
+    #[serde(rename = "channelPattern")]
+    pub channel_pattern: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ChannelToken {
-    #[serde(rename = "channelId")]
-    pub channel_id: String,
+    #[serde(rename = "channelId", skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
+    #[serde(rename = "channelPattern", skip_serializing_if = "Option::is_none")]
+    pub channel_pattern: Option<String>,
     pub token: String,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhookRequest {
+    /// Messages are POSTed here.
+    pub url: String,
+    #[serde(rename = "channelId")]
+    pub channel_id: Option<String>,
+    /// Delivers messages from every channel matching the pattern instead, e.g. `tenant:42:*`.
+    #[serde(rename = "channelPattern")]
+    pub channel_pattern: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookCreated {
+    #[serde(rename = "webhookId")]
+    pub webhook_id: String,
+    /// Key for the HMAC-SHA256 signature of each delivery.
+    pub secret: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PollResponse {
+    /// Pass to the next poll to continue after these messages.
+    pub cursor: String,
+    pub messages: Vec<PolledMessage>,
+    /// Set when messages may have been lost since the last poll.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub missed: bool,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PolledMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "contentType", skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    pub data: String,
+    /// Set when `data` is base64 encoded.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub base64: bool,
+}
+
+/// A channel message with its metadata, sent to `webchannel.v1.json` subscribers.
+#[derive(Debug, Serialize, Clone)]
+pub struct Envelope {
+    /// Set for messages kept in the channel's history.
+    pub id: Option<String>,
+    pub channel: String,
+    pub event: String,
+    /// When the message was published, in unix milliseconds.
+    pub ts: Option<u64>,
+    pub headers: BTreeMap<String, String>,
+    pub data: serde_json::Value,
+    /// Set when `data` is base64 encoded.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub base64: bool,
+}
+
+/// Sent by clients of a multiplexed connection.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MultiplexCommand {
+    Subscribe {
+        channel: String,
+        /// A token created for the channel.
+        token: String,
+        /// Replay messages published after this message ID.
+        #[serde(rename = "lastEventId")]
+        last_event_id: Option<String>,
+        /// Replay messages published since this time, in unix milliseconds.
+        since: Option<u64>,
+    },
+    Unsubscribe {
+        channel: String,
+    },
+}
+
+/// Sent to clients of a multiplexed connection, every one tagged with its channel.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MultiplexReply {
+    Subscribed {
+        channel: String,
+    },
+    Unsubscribed {
+        channel: String,
+    },
+    /// The subscription was lost and made again, messages in between may be missing.
+    Resubscribed {
+        channel: String,
+    },
+    Message(Envelope),
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+        detail: String,
+    },
+}
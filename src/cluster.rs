@@ -0,0 +1,438 @@
+use crate::{
+    connector::Connector,
+    metrics,
+    pool::{self, Pool},
+    pubsub::{Event, Pubsub, ReplyError},
+    settings,
+};
+use anyhow::Context;
+use futures::{SinkExt, StreamExt};
+use parking_lot::{Mutex, RwLock};
+use redis_async::error::Error as RedisError;
+use redis_async::{
+    resp::{FromResp, RespValue},
+    resp_array,
+};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+const SLOTS: usize = 16384;
+const MAX_REDIRECTS: usize = 5;
+
+type OnEvent = Arc<dyn Fn(Event) + Send + Sync>;
+
+/// Computes the cluster hash slot of a key, hashing only its `{tag}` when it has one.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let key = key
+        .iter()
+        .position(|&b| b == b'{')
+        .and_then(|open| {
+            let rest = &key[open + 1..];
+            rest.iter()
+                .position(|&b| b == b'}')
+                .filter(|&close| close > 0)
+                .map(|close| &rest[..close])
+        })
+        .unwrap_or(key);
+    crc16(key) % SLOTS as u16
+}
+
+/// CRC16-XMODEM, as specified for cluster key slots.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[derive(Debug, PartialEq)]
+enum Redirect {
+    Moved(u16, SocketAddr),
+    Ask(SocketAddr),
+}
+
+/// Parses `MOVED <slot> <host:port>` and `ASK <slot> <host:port>` error replies.
+fn parse_redirect(reply: &str) -> Option<Redirect> {
+    let mut parts = reply.split_whitespace();
+    let kind = parts.next()?;
+    let slot = parts
+        .next()?
+        .parse()
+        .ok()
+        .filter(|&slot: &u16| (slot as usize) < SLOTS)?;
+    let address = parts.next()?.parse().ok()?;
+    match kind {
+        "MOVED" => Some(Redirect::Moved(slot, address)),
+        "ASK" => Some(Redirect::Ask(address)),
+        _ => None,
+    }
+}
+
+struct Node {
+    pool: Pool,
+    pubsub: Pubsub,
+}
+
+/// A Redis Cluster client, routing each key to the node owning its slot.
+///
+/// Pub/sub uses sharded channels, so messages only travel within the owning shard.
+pub struct Cluster {
+    connector: Connector,
+    seeds: Vec<SocketAddr>,
+    pool_size: usize,
+    pubsub_connections: usize,
+    slots: RwLock<Vec<Option<SocketAddr>>>,
+    /// Set when the slot map needs loading again before the next lookup.
+    stale: Arc<AtomicBool>,
+    nodes: Mutex<HashMap<SocketAddr, Arc<Node>>>,
+    /// The node each pub/sub key was last subscribed on.
+    subscribed: Arc<Mutex<HashMap<String, SocketAddr>>>,
+    on_event: OnEvent,
+}
+
+impl Cluster {
+    pub fn new(
+        connector: Connector,
+        settings: &settings::Redis,
+        cluster: &settings::Cluster,
+        on_event: impl Fn(Event) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            connector,
+            seeds: cluster.nodes.clone(),
+            pool_size: settings.pool_size,
+            pubsub_connections: settings.pubsub_connections,
+            slots: RwLock::new(vec![None; SLOTS]),
+            stale: Arc::new(AtomicBool::new(true)),
+            nodes: Mutex::new(HashMap::new()),
+            subscribed: Default::default(),
+            on_event: Arc::new(on_event),
+        }
+    }
+
+    fn node(&self, address: SocketAddr) -> Arc<Node> {
+        let mut nodes = self.nodes.lock();
+        if let Some(node) = nodes.get(&address) {
+            return node.clone();
+        }
+
+        let connector = self.connector.direct(address);
+        let pool = Pool::new(pool::Manager::new(connector.clone()), self.pool_size);
+        let pubsub = {
+            let stale = self.stale.clone();
+            let subscribed = self.subscribed.clone();
+            let on_event = self.on_event.clone();
+            Pubsub::new(connector, self.pubsub_connections, true, move |event| {
+                let keys = match &event {
+                    Event::Unsubscribed { key } => std::slice::from_ref(key),
+                    Event::Disconnected { keys, .. } => keys.as_slice(),
+                    Event::Message { .. }
+                    | Event::PatternMessage { .. }
+                    | Event::PatternUnsubscribed { .. } => &[],
+                };
+                if !keys.is_empty() {
+                    // Likely a slot migration or failover, so reload the slot map.
+                    stale.store(true, Ordering::Relaxed);
+                    let mut subscribed = subscribed.lock();
+                    for key in keys {
+                        if subscribed.get(key) == Some(&address) {
+                            subscribed.remove(key);
+                        }
+                    }
+                }
+                on_event(event);
+            })
+        };
+        let node = Arc::new(Node { pool, pubsub });
+        nodes.insert(address, node.clone());
+        node
+    }
+
+    async fn address_for(&self, key: &str) -> anyhow::Result<SocketAddr> {
+        if self.stale.swap(false, Ordering::Relaxed) {
+            if let Err(e) = self.refresh().await {
+                self.stale.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+        let slot = key_slot(key.as_bytes());
+        self.slots.read()[slot as usize]
+            .with_context(|| format!("No cluster node serves slot {}", slot))
+    }
+
+    /// Loads the slot map from the first known node or seed that answers.
+    async fn refresh(&self) -> anyhow::Result<()> {
+        let mut candidates: Vec<SocketAddr> = self.nodes.lock().keys().copied().collect();
+        for seed in &self.seeds {
+            if !candidates.contains(seed) {
+                candidates.push(*seed);
+            }
+        }
+
+        for address in candidates {
+            match query_slots(address, &self.connector.direct(address)).await {
+                Ok(ranges) => {
+                    metrics::REDIS_CLUSTER_SLOT_REFRESHES.inc();
+                    let mut slots = vec![None; SLOTS];
+                    for (start, end, node) in &ranges {
+                        for slot in &mut slots[*start as usize..=*end as usize] {
+                            *slot = Some(*node);
+                        }
+                    }
+                    *self.slots.write() = slots;
+                    info!(
+                        "Loaded redis cluster slot map from {}, {} ranges",
+                        address,
+                        ranges.len()
+                    );
+                    return Ok(());
+                }
+                Err(e) => warn!("Failed loading cluster slots from {}: {:?}", address, e),
+            }
+        }
+        Err(anyhow::anyhow!("No redis cluster node returned a slot map"))
+    }
+
+    fn follow(&self, redirect: &Redirect) -> (SocketAddr, bool) {
+        match *redirect {
+            Redirect::Moved(slot, address) => {
+                metrics::REDIS_CLUSTER_REDIRECTS
+                    .with_label_values(&["moved"])
+                    .inc();
+                self.slots.write()[slot as usize] = Some(address);
+                self.stale.store(true, Ordering::Relaxed);
+                (address, false)
+            }
+            Redirect::Ask(address) => {
+                metrics::REDIS_CLUSTER_REDIRECTS
+                    .with_label_values(&["ask"])
+                    .inc();
+                (address, true)
+            }
+        }
+    }
+
+    /// Sends a command to the node owning `key`, following redirects.
+    pub async fn send<T>(&self, key: &str, command: RespValue) -> anyhow::Result<T>
+    where
+        T: FromResp + Unpin,
+    {
+        let mut replies = self.send_all(key, vec![command]).await?.into_iter();
+        let reply = replies.next().context("Missing redis reply")?;
+        Ok(T::from_resp(reply)?)
+    }
+
+    /// Sends commands about `key` in a row on one connection to the node owning it, following
+    /// redirects, and returns their replies.
+    pub async fn send_all(
+        &self,
+        key: &str,
+        commands: Vec<RespValue>,
+    ) -> anyhow::Result<Vec<RespValue>> {
+        let mut address = self.address_for(key).await?;
+        let mut asking = false;
+        for _ in 0..MAX_REDIRECTS {
+            let node = self.node(address);
+            let connection = node
+                .pool
+                .get()
+                .await
+                .with_context(|| format!("Failed to get redis connection to {}", address))?;
+            let mut sent = vec![];
+            for command in &commands {
+                // ASKING only lets the command straight after it through.
+                let asked = asking.then(|| connection.send::<RespValue>(resp_array!["ASKING"]));
+                sent.push((asked, connection.send::<RespValue>(command.clone())));
+            }
+            let mut replies = vec![];
+            for (asked, reply) in sent {
+                if let Some(asked) = asked {
+                    asked.await?;
+                }
+                replies.push(match reply.await {
+                    Err(RedisError::Remote(reply)) => RespValue::Error(reply),
+                    reply => reply?,
+                });
+            }
+
+            let redirect = replies.iter().find_map(|reply| match reply {
+                RespValue::Error(reply) => parse_redirect(reply),
+                _ => None,
+            });
+            match redirect {
+                Some(redirect) => {
+                    debug!("Following redirect for {:?}: {:?}", key, redirect);
+                    let (next, ask) = self.follow(&redirect);
+                    address = next;
+                    asking = ask;
+                }
+                None => return Ok(replies),
+            }
+        }
+        Err(anyhow::anyhow!("Too many cluster redirects for {:?}", key))
+    }
+
+    /// Subscribes to a sharded channel on the node owning it.
+    ///
+    /// An ASK redirect is treated like MOVED, reloading the slot map and trying again, as ASKING
+    /// can't be sent on a connection in subscribe mode. A subscription failing mid-migration is
+    /// retried once the slot has moved.
+    pub async fn subscribe(&self, key: &str) -> anyhow::Result<()> {
+        let mut address = self.address_for(key).await?;
+        for _ in 0..MAX_REDIRECTS {
+            // Record the node first, so an unsubscribe racing this one finds it.
+            self.subscribed.lock().insert(key.to_owned(), address);
+            let result = self.node(address).pubsub.subscribe(key).await;
+            let redirect = match &result {
+                Err(e) => e
+                    .downcast_ref::<ReplyError>()
+                    .and_then(|ReplyError(reply)| parse_redirect(reply)),
+                Ok(()) => None,
+            };
+            match redirect {
+                Some(redirect @ Redirect::Moved(..)) => address = self.follow(&redirect).0,
+                Some(redirect @ Redirect::Ask(_)) => {
+                    debug!("Subscribing to {:?} was redirected: {:?}", key, redirect);
+                    self.follow(&redirect);
+                    self.stale.store(true, Ordering::Relaxed);
+                    address = self.address_for(key).await?;
+                }
+                None => return result,
+            }
+        }
+        Err(anyhow::anyhow!("Too many cluster redirects for {:?}", key))
+    }
+
+    pub fn unsubscribe(&self, key: &str) {
+        let address = self.subscribed.lock().remove(key);
+        if let Some(address) = address {
+            self.node(address).pubsub.unsubscribe(key);
+        }
+    }
+}
+
+/// Reads `CLUSTER SLOTS` as (first slot, last slot, primary address) ranges.
+async fn query_slots(
+    address: SocketAddr,
+    connector: &Connector,
+) -> anyhow::Result<Vec<(u16, u16, SocketAddr)>> {
+    let mut connection = connector.connect().await?;
+    connection.send(resp_array!["CLUSTER", "SLOTS"]).await?;
+    let reply = connection
+        .next()
+        .await
+        .context("Node closed the connection")??;
+
+    let ranges = match reply {
+        RespValue::Array(ranges) => ranges,
+        RespValue::Error(e) => return Err(anyhow::anyhow!("Redis error: {}", e)),
+        other => return Err(anyhow::anyhow!("Unexpected slots reply: {:?}", other)),
+    };
+    let mut slots = vec![];
+    for range in ranges {
+        let (start, end, host, port) = match range {
+            RespValue::Array(parts) => match parts.as_slice() {
+                [RespValue::Integer(start), RespValue::Integer(end), RespValue::Array(primary), ..] => {
+                    match primary.as_slice() {
+                        [RespValue::BulkString(host), RespValue::Integer(port), ..] => (
+                            *start,
+                            *end,
+                            String::from_utf8_lossy(host).into_owned(),
+                            *port,
+                        ),
+                        _ => return Err(anyhow::anyhow!("Unexpected slot node: {:?}", primary)),
+                    }
+                }
+                _ => return Err(anyhow::anyhow!("Unexpected slot range: {:?}", parts)),
+            },
+            other => return Err(anyhow::anyhow!("Unexpected slot range: {:?}", other)),
+        };
+        let port = u16::try_from(port).context("Invalid node port")?;
+        // An empty host means the node we asked.
+        let node = if host.is_empty() {
+            SocketAddr::new(address.ip(), port)
+        } else {
+            tokio::net::lookup_host((host.as_str(), port))
+                .await?
+                .next()
+                .with_context(|| format!("Node host {:?} did not resolve", host))?
+        };
+        let start = u16::try_from(start).context("Invalid slot")?;
+        let end = u16::try_from(end).context("Invalid slot")?;
+        if start > end || end as usize >= SLOTS {
+            return Err(anyhow::anyhow!("Invalid slot range {}-{}", start, end));
+        }
+        slots.push((start, end, node));
+    }
+    Ok(slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_crc16() {
+        assert_eq!(crc16(b""), 0);
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+    }
+
+    #[test]
+    fn computes_key_slots() {
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"{user}1000"), key_slot(b"{user}1001"));
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+        // Empty or unclosed tags hash the whole key.
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS as u16);
+        assert_eq!(key_slot(b"foo{bar"), crc16(b"foo{bar") % SLOTS as u16);
+    }
+
+    #[test]
+    fn parses_redirects() {
+        let address: SocketAddr = "127.0.0.1:6381".parse().unwrap();
+        assert_eq!(
+            parse_redirect("MOVED 3999 127.0.0.1:6381"),
+            Some(Redirect::Moved(3999, address))
+        );
+        assert_eq!(
+            parse_redirect("ASK 3999 127.0.0.1:6381"),
+            Some(Redirect::Ask(address))
+        );
+        assert_eq!(
+            parse_redirect("MOVED 0 [::1]:6381"),
+            Some(Redirect::Moved(0, "[::1]:6381".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_redirects() {
+        for reply in &[
+            "",
+            "MOVED",
+            "MOVED 3999",
+            "MOVED 16384 127.0.0.1:6381",
+            "MOVED -1 127.0.0.1:6381",
+            "MOVED slot 127.0.0.1:6381",
+            "MOVED 3999 127.0.0.1",
+            "ASK 3999 redis.example.com:6381",
+            "ERR 3999 127.0.0.1:6381",
+            "WRONGTYPE Operation against a key holding the wrong kind of value",
+        ] {
+            assert_eq!(parse_redirect(reply), None, "{:?}", reply);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use crate::settings;
+use anyhow::Context;
+use futures::{select, Future, FutureExt, SinkExt, StreamExt};
+use redis_async::error::{ConnectionReason, Error as RedisError};
+use redis_async::{
+    resp::{FromResp, RespCodec, RespValue},
+    resp_array,
+};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_rustls::{rustls::ClientConfig, webpki::DNSNameRef, TlsConnector};
+use tokio_util::codec::{Decoder, Framed};
+use tracing::debug;
+
+pub trait Io: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Io for T {}
+
+/// A RESP connection over TCP or a Unix socket, optionally with TLS.
+pub type RespConnection = Framed<Box<dyn Io>, RespCodec>;
+
+/// A resolved server to connect to.
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(address) => address.fmt(f),
+            Endpoint::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+/// How to set up each connection to a Redis server.
+#[derive(Clone, Default)]
+pub struct Options {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub database: Option<u32>,
+    pub tls: Option<Tls>,
+}
+
+#[derive(Clone)]
+pub struct Tls {
+    connector: TlsConnector,
+    server_name: String,
+}
+
+impl Tls {
+    /// Verifies servers against the CA bundle when given, or the system roots otherwise.
+    pub fn new(settings: &settings::Tls, server_name: String) -> anyhow::Result<Self> {
+        DNSNameRef::try_from_ascii_str(&server_name).map_err(|_| {
+            anyhow::anyhow!(
+                "Invalid TLS server name {:?}, set redis.tls.server_name to the certificate's DNS name",
+                server_name
+            )
+        })?;
+
+        let mut config = ClientConfig::new();
+        match &settings.ca_file {
+            Some(path) => {
+                let file = File::open(path)
+                    .with_context(|| format!("Failed to open redis CA file {:?}", path))?;
+                let (valid, _) = config
+                    .root_store
+                    .add_pem_file(&mut BufReader::new(file))
+                    .map_err(|_| anyhow::anyhow!("Failed to parse redis CA file {:?}", path))?;
+                if valid == 0 {
+                    return Err(anyhow::anyhow!("No certificates found in {:?}", path));
+                }
+            }
+            None => {
+                config.root_store = rustls_native_certs::load_native_certs()
+                    .map_err(|(_, e)| e)
+                    .context("Failed to load the system root certificates")?;
+            }
+        }
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    async fn connect(&self, stream: Box<dyn Io>) -> io::Result<impl Io> {
+        let name = DNSNameRef::try_from_ascii_str(&self.server_name)
+            .expect("TLS server name was validated");
+        self.connector.connect(name, stream).await
+    }
+}
+
+/// Connects to a Redis server, authenticating and selecting the database as configured.
+pub async fn connect(endpoint: &Endpoint, options: &Options) -> anyhow::Result<RespConnection> {
+    let stream: Box<dyn Io> = match endpoint {
+        Endpoint::Tcp(address) => {
+            let stream = TcpStream::connect(address).await;
+            let stream =
+                stream.with_context(|| format!("Failed to connect to redis at {}", endpoint))?;
+            stream.set_nodelay(true)?;
+            Box::new(stream)
+        }
+        Endpoint::Unix(path) => Box::new(
+            UnixStream::connect(path)
+                .await
+                .with_context(|| format!("Failed to connect to redis at {}", endpoint))?,
+        ),
+    };
+    let stream: Box<dyn Io> = match &options.tls {
+        Some(tls) => Box::new(
+            tls.connect(stream)
+                .await
+                .with_context(|| format!("TLS handshake with redis at {} failed", endpoint))?,
+        ),
+        None => Box::new(stream),
+    };
+    let mut connection = RespCodec.framed(stream);
+
+    if let Some(password) = &options.password {
+        let mut auth = vec!["AUTH".into()];
+        if let Some(username) = &options.username {
+            auth.push(username.as_str().into());
+        }
+        auth.push(password.as_str().into());
+        request::<()>(&mut connection, RespValue::Array(auth))
+            .await
+            .context("Redis authentication failed")?;
+    }
+    if let Some(database) = options.database {
+        request::<()>(&mut connection, resp_array!["SELECT", database.to_string()])
+            .await
+            .with_context(|| format!("Failed to select redis database {}", database))?;
+    }
+    Ok(connection)
+}
+
+/// Sends a command and reads its reply, on a connection with nothing else in flight.
+pub async fn request<T: FromResp>(
+    connection: &mut RespConnection,
+    command: RespValue,
+) -> Result<T, RedisError> {
+    connection.send(command).await?;
+    match connection.next().await {
+        Some(reply) => T::from_resp(reply?),
+        None => Err(RedisError::IO(io::ErrorKind::UnexpectedEof.into())),
+    }
+}
+
+type Reply = oneshot::Sender<Result<RespValue, RedisError>>;
+
+/// A pooled connection, pipelining commands rather than waiting for each reply before sending
+/// the next.
+pub struct Connection {
+    commands: mpsc::UnboundedSender<(RespValue, Reply)>,
+}
+
+impl Connection {
+    pub fn new(inner: RespConnection) -> Self {
+        let (commands, queued) = mpsc::unbounded_channel();
+        tokio::spawn(pair(inner, queued));
+        Self { commands }
+    }
+
+    /// Whether the connection was lost, failing the commands sent on it.
+    pub fn is_broken(&self) -> bool {
+        self.commands.is_closed()
+    }
+
+    /// Queues a command, resolving to its reply.
+    ///
+    /// Commands are written in the order `send` is called, and dropping the returned future
+    /// doesn't disturb the replies to later commands.
+    pub fn send<T: FromResp>(
+        &self,
+        command: RespValue,
+    ) -> impl Future<Output = Result<T, RedisError>> {
+        let (reply, replied) = oneshot::channel();
+        let queued = self.commands.send((command, reply));
+        async move {
+            let lost = || RedisError::Connection(ConnectionReason::NotConnected);
+            queued.map_err(|_| lost())?;
+            T::from_resp(replied.await.map_err(|_| lost())??)
+        }
+    }
+}
+
+/// Writes queued commands and hands each reply to its command, in order, until the connection
+/// fails or is dropped.
+async fn pair(
+    connection: RespConnection,
+    mut commands: mpsc::UnboundedReceiver<(RespValue, Reply)>,
+) {
+    let (mut sink, stream) = connection.split();
+    let mut stream = stream.fuse();
+    let mut waiting: VecDeque<Reply> = VecDeque::new();
+    let mut open = true;
+    let error = loop {
+        let command = async {
+            if open {
+                commands.recv().await
+            } else {
+                futures::future::pending().await
+            }
+        };
+        select! {
+            command = command.fuse() => match command {
+                Some((command, reply)) => match sink.send(command).await {
+                    Ok(()) => waiting.push_back(reply),
+                    Err(e) => {
+                        let error = format!("Failed sending to redis: {}", e);
+                        let _ = reply.send(Err(RedisError::IO(e)));
+                        break error;
+                    }
+                },
+                // Dropped, so finish reading the replies already owed.
+                None if waiting.is_empty() => return,
+                None => open = false,
+            },
+            reply = stream.next() => match (reply, waiting.pop_front()) {
+                (Some(Ok(reply)), Some(waiter)) => {
+                    let _ = waiter.send(Ok(reply));
+                    if !open && waiting.is_empty() {
+                        return;
+                    }
+                }
+                (Some(Ok(reply)), None) => break format!("Unexpected redis reply: {:?}", reply),
+                (Some(Err(e)), waiter) => {
+                    let error = format!("Redis connection error: {}", e);
+                    if let Some(waiter) = waiter {
+                        let _ = waiter.send(Err(e));
+                    }
+                    break error;
+                }
+                (None, waiter) => {
+                    waiting.extend(waiter);
+                    break "Connection closed by redis".to_owned();
+                }
+            },
+        }
+    };
+    debug!("{}", error);
+    commands.close();
+    for waiter in waiting {
+        let _ = waiter.send(Err(RedisError::Internal(error.clone())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A connection to a fake server, which reads commands and writes replies by hand.
+    fn fake_server() -> (Connection, RespConnection) {
+        let (client, server) = tokio::io::duplex(4096);
+        let client: Box<dyn Io> = Box::new(client);
+        let server: Box<dyn Io> = Box::new(server);
+        (
+            Connection::new(RespCodec.framed(client)),
+            RespCodec.framed(server),
+        )
+    }
+
+    #[tokio::test]
+    async fn pipelines_commands() {
+        let (connection, mut server) = fake_server();
+        let first = connection.send::<String>(resp_array!["GET", "a"]);
+        let second = connection.send::<String>(resp_array!["GET", "b"]);
+
+        // Both are written before either is answered
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            resp_array!["GET", "a"]
+        );
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            resp_array!["GET", "b"]
+        );
+        server.send(RespValue::from("1")).await.unwrap();
+        server.send(RespValue::from("2")).await.unwrap();
+        assert_eq!(first.await.unwrap(), "1");
+        assert_eq!(second.await.unwrap(), "2");
+    }
+
+    #[tokio::test]
+    async fn dropped_commands_keep_replies_in_step() {
+        let (connection, mut server) = fake_server();
+        drop(connection.send::<String>(resp_array!["GET", "a"]));
+        let second = connection.send::<String>(resp_array!["GET", "b"]);
+
+        server.next().await.unwrap().unwrap();
+        server.next().await.unwrap().unwrap();
+        server
+            .send(RespValue::Error("ERR nope".to_owned()))
+            .await
+            .unwrap();
+        server.send(RespValue::from("2")).await.unwrap();
+        assert_eq!(second.await.unwrap(), "2");
+        assert!(!connection.is_broken());
+    }
+
+    #[tokio::test]
+    async fn fails_commands_when_closed() {
+        let (connection, mut server) = fake_server();
+        let command = connection.send::<String>(resp_array!["GET", "a"]);
+        server.next().await.unwrap().unwrap();
+        drop(server);
+        assert!(command.await.is_err());
+        assert!(connection.is_broken());
+        assert!(connection
+            .send::<String>(resp_array!["GET", "b"])
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,903 @@
+//! permessage-deflate (RFC 7692) for WebSocket connections.
+//!
+//! warp's WebSockets don't support extensions, so messages are compressed beneath them: once a
+//! connection's upgrade response negotiated the extension, frames are rewritten as they're
+//! written to and read from the socket.
+
+use crate::{metrics, settings};
+use bytes::{Buf, BufMut, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use parking_lot::Mutex;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use warp::http::{header::HeaderValue, Response};
+
+/// Name of the extension in `Sec-WebSocket-Extensions` headers.
+const EXTENSION: &str = "permessage-deflate";
+/// Ends each compressed message, which is left off on the wire.
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+/// Past the largest message any WebSocket route accepts, so bigger messages are refused.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Compressed frames are written to the socket before more are accepted past this.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+/// What HTTP/2 connections open with, ahead of any frames.
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const OPCODE: u8 = 0x0f;
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+/// Set in the opcodes of control frames.
+const OP_CONTROL: u8 = 0x8;
+const MASKED: u8 = 0x80;
+
+/// What was agreed with a client offering permessage-deflate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Params {
+    server_max_window_bits: u8,
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    /// Smaller messages are sent uncompressed.
+    min_size: usize,
+}
+
+impl Params {
+    fn accept(offer: &str, settings: &settings::Deflate) -> Option<Self> {
+        let mut params = offer.split(';').map(str::trim);
+        if params.next()? != EXTENSION {
+            return None;
+        }
+        let mut accepted = Self {
+            server_max_window_bits: settings.window_bits,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            min_size: settings.min_size,
+        };
+        let mut seen = Vec::new();
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            // Each parameter may only be given once.
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+            match (name, value) {
+                ("server_no_context_takeover", None) => accepted.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => accepted.client_no_context_takeover = true,
+                ("server_max_window_bits", Some(bits)) => {
+                    let bits: u8 = bits.parse().ok()?;
+                    // zlib can't compress with 256 byte windows.
+                    if !(9..=15).contains(&bits) {
+                        return None;
+                    }
+                    accepted.server_max_window_bits = accepted.server_max_window_bits.min(bits);
+                }
+                // Client messages are always inflated with the largest window.
+                ("client_max_window_bits", None) => (),
+                ("client_max_window_bits", Some(bits)) => {
+                    let bits: u8 = bits.parse().ok()?;
+                    if !(8..=15).contains(&bits) {
+                        return None;
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(accepted)
+    }
+
+    /// The `Sec-WebSocket-Extensions` response header accepting the offer.
+    fn header(&self) -> String {
+        let mut header = EXTENSION.to_owned();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if self.server_max_window_bits < 15 {
+            header.push_str(&format!(
+                "; server_max_window_bits={}",
+                self.server_max_window_bits
+            ));
+        }
+        header
+    }
+}
+
+/// A client's offer of permessage-deflate, which its connection can honor.
+pub struct Offer {
+    handle: Handle,
+    params: Params,
+}
+
+impl Offer {
+    /// Picks the first offer in a `Sec-WebSocket-Extensions` header that can be honored.
+    pub fn negotiate(offers: &str, handle: Handle, settings: &settings::Deflate) -> Option<Self> {
+        let params = offers
+            .split(',')
+            .find_map(|offer| Params::accept(offer, settings))?;
+        Some(Self { handle, params })
+    }
+
+    /// Accepts the offer in an upgrade response, which must be the connection's next one.
+    pub fn accept<B>(self, response: &mut Response<B>) {
+        let header =
+            HeaderValue::from_str(&self.params.header()).expect("Invalid extension header");
+        response
+            .headers_mut()
+            .insert("sec-websocket-extensions", header);
+        *self.handle.0.lock() = Some(self.params);
+    }
+}
+
+/// Arms a connection to compress WebSocket messages once its upgrade response is written.
+///
+/// Added to the extensions of each request on a connection that can compress.
+#[derive(Clone, Default)]
+pub struct Handle(Arc<Mutex<Option<Params>>>);
+
+impl Handle {
+    fn take(&self) -> Option<Params> {
+        self.0.lock().take()
+    }
+
+    fn is_armed(&self) -> bool {
+        self.0.lock().is_some()
+    }
+}
+
+enum Mode {
+    /// Passing HTTP through, collecting the head of an armed upgrade response.
+    ///
+    /// Reads stop at the end of each request head, keeping what follows for the next read. A
+    /// client sending frames straight after its upgrade request would otherwise have them read
+    /// ahead as HTTP, and handed on still compressed. HTTP/2 connections, which can't upgrade,
+    /// are read as they come: their preface looks like a head, and must be read whole.
+    Http {
+        response_head: Vec<u8>,
+        read_ahead: BytesMut,
+        /// How much of a head's closing `\r\n\r\n` the bytes read so far end with, or `None`
+        /// once the connection turned out to be HTTP/2.
+        head_end: Option<usize>,
+        /// Whether nothing has been read yet.
+        first_read: bool,
+    },
+    WebSocket(Box<Codec>),
+}
+
+/// A connection that compresses WebSocket messages after an upgrade negotiating it.
+pub struct Stream<IO> {
+    io: IO,
+    handle: Handle,
+    mode: Mode,
+}
+
+impl<IO> Stream<IO> {
+    pub fn new(io: IO) -> Self {
+        Self {
+            io,
+            handle: Handle::default(),
+            mode: Mode::Http {
+                response_head: Vec::new(),
+                read_ahead: BytesMut::new(),
+                head_end: Some(0),
+                first_read: true,
+            },
+        }
+    }
+
+    pub fn get_ref(&self) -> &IO {
+        &self.io
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Stream<IO> {
+    /// Writes out compressed frames.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let codec = match &mut self.mode {
+            Mode::WebSocket(codec) => codec,
+            Mode::Http { .. } => return Poll::Ready(Ok(())),
+        };
+        while !codec.write_buf.is_empty() {
+            match Pin::new(&mut self.io).poll_write(cx, &codec.write_buf) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => codec.write_buf.advance(n),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Writes an armed upgrade response's head, switching to WebSocket frames after it.
+    fn poll_write_head(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let (response_head, read_ahead) = match &mut self.mode {
+            Mode::Http {
+                response_head,
+                read_ahead,
+                ..
+            } => (response_head, read_ahead),
+            Mode::WebSocket(_) => unreachable!("Not writing HTTP"),
+        };
+        // Don't write past the head, so nothing after it is left uncompressed.
+        let mut len = buf.len();
+        for (i, byte) in buf.iter().enumerate() {
+            response_head.push(*byte);
+            if response_head.ends_with(b"\r\n\r\n") {
+                len = i + 1;
+                break;
+            }
+        }
+        let n = match Pin::new(&mut self.io).poll_write(cx, &buf[..len]) {
+            Poll::Ready(Ok(n)) => n,
+            other => {
+                response_head.truncate(response_head.len() - len);
+                return other;
+            }
+        };
+        response_head.truncate(response_head.len() - (len - n));
+        if response_head.ends_with(b"\r\n\r\n") {
+            let upgraded = response_head.starts_with(b"HTTP/1.1 101 ");
+            response_head.clear();
+            if let Some(params) = self.handle.take() {
+                if upgraded {
+                    let mut codec = Codec::new(params);
+                    codec.read_raw = read_ahead.split();
+                    self.mode = Mode::WebSocket(Box::new(codec));
+                }
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for Stream<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let codec = match &mut this.mode {
+            Mode::WebSocket(codec) => codec,
+            Mode::Http {
+                read_ahead,
+                head_end,
+                first_read,
+                ..
+            } => {
+                if !read_ahead.is_empty() {
+                    let available = read_ahead.len().min(buf.remaining());
+                    let n = head_end
+                        .as_mut()
+                        .and_then(|matched| find_head_end(matched, &read_ahead[..available]))
+                        .unwrap_or(available);
+                    buf.put_slice(&read_ahead.split_to(n));
+                    return Poll::Ready(Ok(()));
+                }
+                let filled = buf.filled().len();
+                match Pin::new(&mut this.io).poll_read(cx, buf) {
+                    Poll::Ready(Ok(())) => (),
+                    other => return other,
+                }
+                let read = &buf.filled()[filled..];
+                if *first_read && !read.is_empty() {
+                    *first_read = false;
+                    let len = read.len().min(HTTP2_PREFACE.len());
+                    if read[..len] == HTTP2_PREFACE[..len] {
+                        *head_end = None;
+                    }
+                }
+                let end = head_end
+                    .as_mut()
+                    .and_then(|matched| find_head_end(matched, read));
+                if let Some(n) = end {
+                    read_ahead.extend_from_slice(&buf.filled()[filled + n..]);
+                    buf.set_filled(filled + n);
+                }
+                return Poll::Ready(Ok(()));
+            }
+        };
+        loop {
+            if !codec.read_buf.is_empty() {
+                let n = codec.read_buf.len().min(buf.remaining());
+                buf.put_slice(&codec.read_buf[..n]);
+                codec.read_buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(frame) = Frame::parse(&mut codec.read_raw)? {
+                codec.read_frame(frame)?;
+                continue;
+            }
+            let mut chunk = [0; READ_CHUNK_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.io).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(())) => (),
+                other => return other,
+            }
+            if chunk.filled().is_empty() {
+                // Let the WebSocket see whatever was left, and the end of the connection.
+                let rest = codec.read_raw.split();
+                codec.read_buf.unsplit(rest);
+                if codec.read_buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+            codec.read_raw.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let Mode::Http { .. } = this.mode {
+            if !this.handle.is_armed() {
+                return Pin::new(&mut this.io).poll_write(cx, buf);
+            }
+            return this.poll_write_head(cx, buf);
+        }
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        let codec = match &mut this.mode {
+            Mode::WebSocket(codec) => codec,
+            Mode::Http { .. } => unreachable!("Not writing WebSocket frames"),
+        };
+        if codec.write_buf.len() >= WRITE_BUFFER_SIZE {
+            return Poll::Pending;
+        }
+        codec.write_raw.extend_from_slice(buf);
+        while let Some(frame) = Frame::parse(&mut codec.write_raw)? {
+            codec.write_frame(frame)?;
+        }
+        // Start writing, though the frames are flushed later.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.io).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.io).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+/// A WebSocket frame, with its payload unmasked.
+struct Frame {
+    /// FIN, the reserved bits, and the opcode.
+    head: u8,
+    masked: bool,
+    payload: BytesMut,
+}
+
+impl Frame {
+    /// Takes the next whole frame from the start of `buf`.
+    fn parse(buf: &mut BytesMut) -> io::Result<Option<Self>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let masked = buf[1] & MASKED != 0;
+        let (len, mut offset) = match buf[1] & 0x7f {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        if len > MAX_MESSAGE_SIZE as u64 {
+            return Err(invalid_data("WebSocket frame too big"));
+        }
+        let mask = if !masked {
+            None
+        } else if buf.len() < offset + 4 {
+            return Ok(None);
+        } else {
+            let mut mask = [0; 4];
+            mask.copy_from_slice(&buf[offset..offset + 4]);
+            offset += 4;
+            Some(mask)
+        };
+        let len = len as usize;
+        if buf.len() < offset + len {
+            return Ok(None);
+        }
+        let head = buf[0];
+        buf.advance(offset);
+        let mut payload = buf.split_to(len);
+        if let Some(mask) = mask {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        Ok(Some(Self {
+            head,
+            masked,
+            payload,
+        }))
+    }
+
+    fn fin(&self) -> bool {
+        self.head & FIN != 0
+    }
+
+    fn rsv1(&self) -> bool {
+        self.head & RSV1 != 0
+    }
+
+    fn opcode(&self) -> u8 {
+        self.head & OPCODE
+    }
+
+    /// Appends the frame to `buf`, masked with zeros if it was masked, which leaves the
+    /// payload as is.
+    fn write(&self, buf: &mut BytesMut) {
+        let mask_bit = if self.masked { MASKED } else { 0 };
+        let len = self.payload.len();
+        buf.reserve(len + 14);
+        buf.put_u8(self.head);
+        if len < 126 {
+            buf.put_u8(mask_bit | len as u8);
+        } else if len <= u16::MAX as usize {
+            buf.put_u8(mask_bit | 126);
+            buf.put_u16(len as u16);
+        } else {
+            buf.put_u8(mask_bit | 127);
+            buf.put_u64(len as u64);
+        }
+        if self.masked {
+            buf.put_slice(&[0; 4]);
+        }
+        buf.put_slice(&self.payload);
+    }
+}
+
+/// Compression state for a connection.
+struct Codec {
+    params: Params,
+    compress: Compress,
+    decompress: Decompress,
+    /// Frames being written by the WebSocket, and compressed frames to write to the socket.
+    write_raw: BytesMut,
+    write_buf: BytesMut,
+    /// Frames read from the socket, and inflated frames for the WebSocket to read.
+    read_raw: BytesMut,
+    read_buf: BytesMut,
+    /// The first frame of a compressed message being read, and the payloads so far.
+    inflating: Option<Frame>,
+}
+
+impl Codec {
+    fn new(params: Params) -> Self {
+        Self {
+            params,
+            compress: Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                params.server_max_window_bits,
+            ),
+            decompress: Decompress::new(false),
+            write_raw: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            read_raw: BytesMut::new(),
+            read_buf: BytesMut::new(),
+            inflating: None,
+        }
+    }
+
+    /// Compresses unfragmented messages large enough to be worth it.
+    fn write_frame(&mut self, mut frame: Frame) -> io::Result<()> {
+        let compressible = frame.fin()
+            && !frame.rsv1()
+            && (frame.opcode() == OP_TEXT || frame.opcode() == OP_BINARY)
+            && frame.payload.len() >= self.params.min_size;
+        if compressible {
+            let compressed = self.deflate(&frame.payload)?;
+            metrics::WEBSOCKET_UNCOMPRESSED_BYTES.inc_by(frame.payload.len() as u64);
+            metrics::WEBSOCKET_COMPRESSED_BYTES.inc_by(compressed.len() as u64);
+            frame.head |= RSV1;
+            frame.payload = compressed;
+        }
+        frame.write(&mut self.write_buf);
+        Ok(())
+    }
+
+    /// Inflates compressed messages, passing anything else through.
+    fn read_frame(&mut self, frame: Frame) -> io::Result<()> {
+        let mut first = match self.inflating.take() {
+            Some(mut first) if frame.opcode() == OP_CONTINUATION && !frame.rsv1() => {
+                if first.payload.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                    return Err(invalid_data("WebSocket message too big"));
+                }
+                first.payload.extend_from_slice(&frame.payload);
+                first.head |= frame.head & FIN;
+                first
+            }
+            Some(first) if frame.opcode() & OP_CONTROL != 0 => {
+                // Control frames may come between the fragments of a message.
+                self.inflating = Some(first);
+                frame.write(&mut self.read_buf);
+                return Ok(());
+            }
+            Some(_) => return Err(invalid_data("Expected a continuation frame")),
+            // Compressed messages are marked on their first frame.
+            None if frame.rsv1() && (frame.opcode() == OP_TEXT || frame.opcode() == OP_BINARY) => {
+                frame
+            }
+            None => {
+                frame.write(&mut self.read_buf);
+                return Ok(());
+            }
+        };
+        if !first.fin() {
+            self.inflating = Some(first);
+            return Ok(());
+        }
+        first.payload = self.inflate(&first.payload)?;
+        first.head &= !RSV1;
+        first.write(&mut self.read_buf);
+        Ok(())
+    }
+
+    fn deflate(&mut self, payload: &[u8]) -> io::Result<BytesMut> {
+        let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            let consumed = (self.compress.total_in() - start) as usize;
+            // Flushed once there's room left over.
+            if consumed == payload.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity());
+        }
+        if out.ends_with(&DEFLATE_TAIL) {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+        if self.params.server_no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(out.as_slice().into())
+    }
+
+    fn inflate(&mut self, payload: &[u8]) -> io::Result<BytesMut> {
+        let mut input = Vec::with_capacity(payload.len() + DEFLATE_TAIL.len());
+        input.extend_from_slice(payload);
+        input.extend_from_slice(&DEFLATE_TAIL);
+        let mut out = Vec::with_capacity(payload.len() * 2 + 64);
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(invalid_data)?;
+            if out.len() > MAX_MESSAGE_SIZE {
+                return Err(invalid_data("WebSocket message too big"));
+            }
+            if status == Status::StreamEnd {
+                // The client ended its stream, so the next message starts a new one.
+                self.decompress.reset(false);
+                break;
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if consumed == input.len() && out.len() < out.capacity() {
+                break;
+            }
+            if status == Status::BufError && out.len() < out.capacity() {
+                return Err(invalid_data("Truncated compressed message"));
+            }
+            out.reserve(out.capacity());
+        }
+        Ok(out.as_slice().into())
+    }
+}
+
+/// Finds the end of an HTTP head in `bytes`, carrying how much of `\r\n\r\n` was matched
+/// over from earlier bytes in `matched`.
+fn find_head_end(matched: &mut usize, bytes: &[u8]) -> Option<usize> {
+    for (i, byte) in bytes.iter().enumerate() {
+        *matched = match (*matched, byte) {
+            (0 | 2, b'\r') => *matched + 1,
+            (1 | 3, b'\n') => *matched + 1,
+            (_, b'\r') => 1,
+            _ => 0,
+        };
+        if *matched == 4 {
+            *matched = 0;
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const SETTINGS: settings::Deflate = settings::Deflate {
+        enabled: true,
+        window_bits: 15,
+        min_size: 0,
+    };
+
+    fn params(no_context_takeover: bool) -> Params {
+        Params {
+            server_max_window_bits: 15,
+            server_no_context_takeover: no_context_takeover,
+            client_no_context_takeover: no_context_takeover,
+            min_size: 0,
+        }
+    }
+
+    fn frame(head: u8, payload: &[u8]) -> Frame {
+        Frame {
+            head,
+            masked: true,
+            payload: payload.into(),
+        }
+    }
+
+    /// Compresses a message as a client would, with a codec of its own.
+    fn compress(codec: &mut Codec, payload: &[u8]) -> BytesMut {
+        codec.deflate(payload).unwrap()
+    }
+
+    /// The frames a codec passed on to the WebSocket.
+    fn read_frames(codec: &mut Codec) -> Vec<Frame> {
+        let mut frames = vec![];
+        while let Some(frame) = Frame::parse(&mut codec.read_buf).unwrap() {
+            frames.push(frame);
+        }
+        assert!(codec.read_buf.is_empty());
+        frames
+    }
+
+    #[test]
+    fn accepts_offers() {
+        let accept = |offer| Params::accept(offer, &SETTINGS);
+        assert_eq!(accept("permessage-deflate"), Some(params(false)));
+        assert_eq!(
+            accept("permessage-deflate; client_max_window_bits"),
+            Some(params(false))
+        );
+        let accepted = accept(
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover; \
+             server_max_window_bits=10; client_max_window_bits=\"8\"",
+        )
+        .unwrap();
+        assert!(accepted.server_no_context_takeover && accepted.client_no_context_takeover);
+        assert_eq!(accepted.server_max_window_bits, 10);
+        assert_eq!(
+            accepted.header(),
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover; \
+             server_max_window_bits=10"
+        );
+
+        // Windows are never bigger than configured.
+        let settings = settings::Deflate {
+            window_bits: 12,
+            ..SETTINGS
+        };
+        let accepted = Params::accept("permessage-deflate; server_max_window_bits=14", &settings);
+        assert_eq!(accepted.unwrap().server_max_window_bits, 12);
+    }
+
+    #[test]
+    fn rejects_invalid_offers() {
+        for offer in &[
+            "x-webkit-deflate-frame",
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; client_max_window_bits; client_max_window_bits=10",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; server_max_window_bits=8",
+            "permessage-deflate; server_max_window_bits=16",
+            "permessage-deflate; server_max_window_bits=ten",
+            "permessage-deflate; client_max_window_bits=7",
+            "permessage-deflate; client_max_window_bits=16",
+            "permessage-deflate; server_no_context_takeover=1",
+            "permessage-deflate; unknown",
+        ] {
+            assert_eq!(Params::accept(offer, &SETTINGS), None, "{:?}", offer);
+        }
+        let offers = "permessage-deflate; server_max_window_bits=16, permessage-deflate";
+        assert!(Offer::negotiate(offers, Handle::default(), &SETTINGS).is_some());
+    }
+
+    #[test]
+    fn round_trips_with_context_takeover() {
+        let (mut server, mut client) = (Codec::new(params(false)), Codec::new(params(false)));
+        let message = b"progress progress progress progress";
+        let first = compress(&mut server, message);
+        let second = compress(&mut server, message);
+        // The second message refers back to the first.
+        assert!(second.len() < first.len());
+        assert_eq!(client.inflate(&first).unwrap(), &message[..]);
+        assert_eq!(client.inflate(&second).unwrap(), &message[..]);
+    }
+
+    #[test]
+    fn round_trips_without_context_takeover() {
+        let mut server = Codec::new(params(true));
+        let message = b"progress progress progress progress";
+        let first = compress(&mut server, message);
+        let second = compress(&mut server, message);
+        assert_eq!(first, second);
+        // Each message inflates on its own.
+        assert_eq!(
+            Codec::new(params(true)).inflate(&second).unwrap(),
+            &message[..]
+        );
+    }
+
+    #[test]
+    fn compresses_written_messages() {
+        let mut server = Codec::new(params(false));
+        server
+            .write_frame(frame(FIN | OP_TEXT, b"hello hello hello"))
+            .unwrap();
+        // Control frames go as they are.
+        server
+            .write_frame(frame(FIN | OP_CONTROL | 0x9, b"ping"))
+            .unwrap();
+
+        let compressed = Frame::parse(&mut server.write_buf).unwrap().unwrap();
+        assert_eq!(compressed.head, FIN | RSV1 | OP_TEXT);
+        let mut client = Codec::new(params(false));
+        assert_eq!(
+            client.inflate(&compressed.payload).unwrap(),
+            &b"hello hello hello"[..]
+        );
+        let ping = Frame::parse(&mut server.write_buf).unwrap().unwrap();
+        assert_eq!(ping.head, FIN | OP_CONTROL | 0x9);
+        assert_eq!(ping.payload, &b"ping"[..]);
+    }
+
+    #[test]
+    fn inflates_fragmented_messages() {
+        let mut client = Codec::new(params(false));
+        let compressed = compress(&mut client, b"hello fragmented world");
+        let (start, rest) = compressed.split_at(3);
+        let (middle, end) = rest.split_at(3);
+
+        let mut server = Codec::new(params(false));
+        server.read_frame(frame(RSV1 | OP_TEXT, start)).unwrap();
+        server.read_frame(frame(OP_CONTINUATION, middle)).unwrap();
+        assert!(read_frames(&mut server).is_empty());
+        server
+            .read_frame(frame(FIN | OP_CONTINUATION, end))
+            .unwrap();
+
+        let frames = read_frames(&mut server);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].head, FIN | OP_TEXT);
+        assert_eq!(frames[0].payload, &b"hello fragmented world"[..]);
+    }
+
+    #[test]
+    fn passes_control_frames_between_fragments() {
+        let mut client = Codec::new(params(false));
+        let compressed = compress(&mut client, b"hello fragmented world");
+        let (start, end) = compressed.split_at(4);
+
+        let mut server = Codec::new(params(false));
+        server.read_frame(frame(RSV1 | OP_BINARY, start)).unwrap();
+        server
+            .read_frame(frame(FIN | OP_CONTROL | 0x9, b"ping"))
+            .unwrap();
+        server
+            .read_frame(frame(FIN | OP_CONTINUATION, end))
+            .unwrap();
+
+        let frames = read_frames(&mut server);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].head, FIN | OP_CONTROL | 0x9);
+        assert_eq!(frames[0].payload, &b"ping"[..]);
+        assert_eq!(frames[1].head, FIN | OP_BINARY);
+        assert_eq!(frames[1].payload, &b"hello fragmented world"[..]);
+
+        // A new message can't start before the last one ends.
+        server.read_frame(frame(RSV1 | OP_TEXT, start)).unwrap();
+        assert!(server.read_frame(frame(FIN | OP_TEXT, b"x")).is_err());
+    }
+
+    #[test]
+    fn finds_head_ends_across_reads() {
+        let mut matched = 0;
+        assert_eq!(find_head_end(&mut matched, b"GET / HTTP/1.1\r\n\r"), None);
+        assert_eq!(find_head_end(&mut matched, b"\nframes"), Some(1));
+        assert_eq!(find_head_end(&mut matched, b"\r\r\n\r\n"), Some(5));
+        assert_eq!(find_head_end(&mut matched, b"\r\n\n\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn reads_http2_prefaces_whole() {
+        let (io, mut client) = tokio::io::duplex(4096);
+        let mut server = Stream::new(io);
+
+        client.write_all(HTTP2_PREFACE).await.unwrap();
+        let mut read = vec![0; 4096];
+        let n = server.read(&mut read).await.unwrap();
+        assert_eq!(&read[..n], HTTP2_PREFACE);
+    }
+
+    #[tokio::test]
+    async fn inflates_frames_sent_straight_after_the_upgrade_request() {
+        let (io, mut client) = tokio::io::duplex(4096);
+        let mut server = Stream::new(io);
+
+        let mut deflater = Codec::new(params(false));
+        let mut sent = BytesMut::from(&b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\n\r\n"[..]);
+        frame(FIN | RSV1 | OP_TEXT, &compress(&mut deflater, b"early")).write(&mut sent);
+        client.write_all(&sent).await.unwrap();
+
+        // Only the request is read as HTTP.
+        let mut request = vec![0; 4096];
+        let n = server.read(&mut request).await.unwrap();
+        assert!(request[..n].ends_with(b"\r\n\r\n"));
+
+        *server.handle().0.lock() = Some(params(false));
+        server
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(matches!(server.mode, Mode::WebSocket(_)));
+
+        let mut read = BytesMut::new();
+        let frame = loop {
+            if let Some(frame) = Frame::parse(&mut read).unwrap() {
+                break frame;
+            }
+            let mut chunk = [0; 64];
+            let n = server.read(&mut chunk).await.unwrap();
+            read.extend_from_slice(&chunk[..n]);
+        };
+        assert_eq!(frame.head, FIN | OP_TEXT);
+        assert_eq!(frame.payload, &b"early"[..]);
+    }
+}
@@ -1,25 +1,38 @@
 use crate::{
+    broker::{self, Broker},
     jwt::Jwt,
-    pool::{self, Pool},
+    metrics,
+    poll::Polls,
     settings::Settings,
+    shutdown::Shutdown,
+    webhook::Webhooks,
 };
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct Environment {
     pub settings: Settings,
     pub jwt: Jwt,
-    pub redis_pool: Pool,
+    pub broker: Arc<dyn Broker>,
+    pub polls: Polls,
+    pub webhooks: Webhooks,
+    pub shutdown: Shutdown,
 }
 
 impl Environment {
     pub async fn new(settings: Settings) -> anyhow::Result<Self> {
-        let pool_mgr = pool::Manager::new(settings.redis.address);
-        let redis_pool = pool::Pool::new(pool_mgr, settings.redis.pool_size);
+        metrics::count_subscribers_by_channel(settings.metrics.channel_subscribers);
+        let broker = broker::from_settings(&settings).await?;
         let jwt = Jwt::new(settings.channel.secret_key.as_str());
+        let polls = Polls::new(&settings.poll)?;
+        let webhooks = Webhooks::new(&settings.webhooks)?;
         Ok(Self {
             settings,
             jwt,
-            redis_pool,
+            broker,
+            polls,
+            webhooks,
+            shutdown: Shutdown::new(),
         })
     }
 }
@@ -4,4 +4,16 @@ use thiserror::Error;
 pub enum RequestError {
     #[error("payload too large, limit: {limit:?}")]
     PayloadTooLarge { limit: usize },
+    #[error("invalid parameter {name:?}: {reason}")]
+    InvalidParameter { name: &'static str, reason: String },
+    #[error("channel has no subscribers")]
+    NoSubscribers,
+    #[error("poll cursor expired")]
+    CursorExpired,
+    #[error("too many poll sessions")]
+    TooManyPolls { per_channel: bool },
+    #[error("webhook not found")]
+    WebhookNotFound,
+    #[error("server is shutting down")]
+    ShuttingDown,
 }
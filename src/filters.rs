@@ -1,20 +1,102 @@
-use crate::{auth, channel, environment::Environment, handlers, metrics, problem, settings};
+use crate::{
+    auth,
+    broker::{self, HistoryStart},
+    channel, deflate,
+    environment::Environment,
+    error::RequestError,
+    handlers, problem, settings,
+    shutdown::Shutdown,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use tracing::error;
 use tracing::{debug, trace};
-use warp::{Filter, Rejection, Reply};
+use warp::{filters::BoxedFilter, Filter, Rejection, Reply};
 
 const MAX_MESSAGE_SIZE: usize = 1024 * 512;
+const MAX_COMMAND_SIZE: usize = 1024 * 16;
 const BEARER: &str = "Bearer ";
+/// WebSocket subprotocol asking for text frames, like `frames=text`.
+const TEXT_SUBPROTOCOL: &str = "webchannel.v1.text";
+/// WebSocket subprotocol asking for JSON envelopes, like `frames=json`.
+const JSON_SUBPROTOCOL: &str = "webchannel.v1.json";
+/// Prefixes publish request headers attached to the message, without the prefix.
+const MESSAGE_HEADER_PREFIX: &str = "x-header-";
 
 #[derive(Deserialize, Serialize)]
 struct AuthQuery {
     access_token: String,
 }
 
-pub fn webchannel(
-    environment: Environment,
-) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+#[derive(Deserialize, Serialize)]
+struct HistoryQuery {
+    /// Replay messages published after this message ID.
+    last_event_id: Option<String>,
+    /// Replay messages published since this time, in unix milliseconds.
+    since: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct PublishQuery {
+    /// Fail with a conflict when no subscriber receives the message.
+    #[serde(default)]
+    require_subscribers: bool,
+    /// Names the kind of message, for clients of the JSON envelope.
+    event: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    #[serde(default)]
+    encoding: handlers::EventEncoding,
+}
+
+#[derive(Deserialize)]
+struct PollQuery {
+    /// Returned by the previous poll, or unset to start polling.
+    cursor: Option<String>,
+    #[serde(default)]
+    encoding: handlers::EventEncoding,
+}
+
+#[derive(Deserialize)]
+struct FramesQuery {
+    #[serde(default)]
+    frames: handlers::FrameType,
+}
+
+#[derive(Deserialize, Serialize)]
+struct NoticeQuery {
+    /// Send a text frame notice when a lost subscription is recovered.
+    #[serde(default)]
+    resubscribed_notice: bool,
+}
+
+impl HistoryQuery {
+    fn start(self) -> Result<Option<HistoryStart>, RequestError> {
+        if let Some(id) = self.last_event_id {
+            return match id.parse() {
+                Ok(id) => Ok(Some(HistoryStart::After(id))),
+                Err(e) => Err(RequestError::InvalidParameter {
+                    name: "last_event_id",
+                    reason: e.to_string(),
+                }),
+            };
+        }
+        match self.since.map(|since| since.parse()) {
+            Some(Ok(millis)) => Ok(Some(HistoryStart::Since(millis))),
+            Some(Err(e)) => Err(RequestError::InvalidParameter {
+                name: "since",
+                reason: format!("{}", e),
+            }),
+            None => Ok(None),
+        }
+    }
+}
+
+pub fn webchannel(environment: Environment) -> BoxedFilter<(warp::reply::Response,)> {
+    let deflate = deflate_offer(environment.settings.deflate.clone());
+    let upgrade = upgrade(environment.shutdown.clone());
     let with_env = warp::any().map(move || environment.clone());
 
     let api_key_auth = warp::header::optional("x-api-key")
@@ -64,36 +146,99 @@ pub fn webchannel(
         .and(warp::path::end())
         .and(warp::post())
         .and(with_limited_body(MAX_MESSAGE_SIZE))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(message_headers())
+        .and(warp::query::<PublishQuery>())
         .and(with_env.clone())
         .and(valid_auth_header.or(api_key_auth.clone()))
-        .and_then(|channel: String, body, env, _auth| async move {
-            handlers::publish(channel.as_str(), body, env)
-                .await
-                .map_err(problem::build)
+        .and_then(
+            |channel: String,
+             body: Vec<u8>,
+             content_type,
+             headers,
+             query: PublishQuery,
+             env,
+             _auth| async move {
+                let message = broker::Message {
+                    content_type,
+                    event: query.event,
+                    headers,
+                    ..broker::Message::new(body.into())
+                };
+                handlers::publish(channel.as_str(), message, query.require_subscribers, env)
+                    .await
+                    .map_err(problem::build)
+            },
+        );
+
+    let history_start = warp::query::<HistoryQuery>()
+        .and_then(|q: HistoryQuery| async move { q.start().map_err(problem::build) });
+
+    // EventSource sends the last ID it saw when reconnecting, which takes precedence.
+    let event_history_start = warp::sse::last_event_id::<String>()
+        .and(history_start)
+        .and_then(|last_event_id: Option<String>, start| async move {
+            match last_event_id {
+                Some(id) => match id.parse() {
+                    Ok(id) => Ok(Some(HistoryStart::After(id))),
+                    Err(e) => Err(problem::build(RequestError::InvalidParameter {
+                        name: "last_event_id",
+                        reason: e.to_string(),
+                    })),
+                },
+                None => Ok(start),
+            }
         });
 
     let subscribe = channel_param()
         // let subscribe = warp::path::param::<String>()
         .and(warp::path::end())
-        .and(any_token_auth)
-        .and(warp::ws())
+        // Check for an upgrade first, so other requests don't end up as auth failures here.
+        .and(upgrade.clone())
+        .and(any_token_auth.clone())
+        .and(history_start)
+        .and(frame_type())
+        .and(warp::query::<NoticeQuery>())
+        .and(deflate.clone())
         .and(with_env.clone())
         .and_then(
             |channel_id: String,
-             claims: biscuit::ClaimsSet<auth::Claims>,
              ws: warp::ws::Ws,
+             claims: biscuit::ClaimsSet<auth::Claims>,
+             start: Option<HistoryStart>,
+             frames: handlers::FrameType,
+             subprotocol: Option<&'static str>,
+             notices: NoticeQuery,
+             deflate: Option<deflate::Offer>,
              env| async move {
                 if channel_id == claims.private.cid {
                     trace!("Channel matches claim, allowing upgrade");
-                    let reply = ws.max_message_size(MAX_MESSAGE_SIZE as usize).on_upgrade(
+                    let reply = ws.max_message_size(MAX_MESSAGE_SIZE).on_upgrade(
                         move |websocket| async move {
-                            metrics::USERS_CONNECTED.inc();
-                            if let Err(e) = handlers::subscribe(&channel_id, env, websocket).await {
+                            if let Err(e) = handlers::subscribe(
+                                &channel_id,
+                                start,
+                                frames,
+                                notices.resubscribed_notice,
+                                env,
+                                websocket,
+                            )
+                            .await
+                            {
                                 error!("Subscribe error on channel {:?}: {:?}", &channel_id, e);
                             }
-                            metrics::USERS_CONNECTED.dec();
                         },
                     );
+                    let mut reply = reply.into_response();
+                    if let Some(subprotocol) = subprotocol {
+                        reply.headers_mut().insert(
+                            "sec-websocket-protocol",
+                            warp::http::HeaderValue::from_static(subprotocol),
+                        );
+                    }
+                    if let Some(deflate) = deflate {
+                        deflate.accept(&mut reply);
+                    }
                     Ok(reply)
                 } else {
                     debug!(
@@ -105,6 +250,132 @@ pub fn webchannel(
             },
         );
 
+    let multiplex = warp::path::end()
+        .and(upgrade.clone())
+        .and(deflate.clone())
+        .and(with_env.clone())
+        .map(|ws: warp::ws::Ws, deflate: Option<deflate::Offer>, env| {
+            // Client frames are only commands, so they're kept small.
+            let mut reply = ws
+                .max_message_size(MAX_COMMAND_SIZE)
+                .on_upgrade(move |websocket| handlers::multiplex(env, websocket))
+                .into_response();
+            if let Some(deflate) = deflate {
+                deflate.accept(&mut reply);
+            }
+            reply
+        });
+
+    let psubscribe = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(upgrade.clone())
+        .and(any_token_auth.clone())
+        .and(deflate.clone())
+        .and(with_env.clone())
+        .and_then(
+            |pattern: String,
+             ws: warp::ws::Ws,
+             claims: biscuit::ClaimsSet<auth::Claims>,
+             deflate: Option<deflate::Offer>,
+             env| async move {
+                let pattern = check_pattern_claim(&pattern, &claims)?;
+                let reply =
+                    ws.max_message_size(MAX_MESSAGE_SIZE)
+                        .on_upgrade(move |websocket| async move {
+                            if let Err(e) = handlers::psubscribe(&pattern, env, websocket).await {
+                                error!("Subscribe error on channel pattern {:?}: {:?}", pattern, e);
+                            }
+                        });
+                let mut reply = reply.into_response();
+                if let Some(deflate) = deflate {
+                    deflate.accept(&mut reply);
+                }
+                Ok::<_, Rejection>(reply)
+            },
+        );
+
+    let subscribe_events = channel_param()
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(accepts_event_stream())
+        .and(any_token_auth.clone())
+        .and(event_history_start)
+        .and(warp::query::<EventsQuery>())
+        .and(with_env.clone())
+        .and_then(
+            |channel_id: String,
+             claims: biscuit::ClaimsSet<auth::Claims>,
+             start: Option<HistoryStart>,
+             query: EventsQuery,
+             env| async move {
+                check_channel_claim(&channel_id, &claims)?;
+                handlers::subscribe_events(&channel_id, start, query.encoding, env)
+                    .await
+                    .map_err(problem::build)
+            },
+        );
+
+    let poll = channel_param()
+        .and(warp::path("poll"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(any_token_auth)
+        .and(history_start)
+        .and(warp::query::<PollQuery>())
+        .and(with_env.clone())
+        .and_then(
+            |channel_id: String,
+             claims: biscuit::ClaimsSet<auth::Claims>,
+             start: Option<HistoryStart>,
+             query: PollQuery,
+             env| async move {
+                check_channel_claim(&channel_id, &claims)?;
+                handlers::poll(&channel_id, query.cursor, start, query.encoding, env)
+                    .await
+                    .map_err(problem::build)
+            },
+        );
+
+    let channel_stats = channel_param()
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_env.clone())
+        .and(api_key_auth.clone())
+        .and_then(|channel: String, env, _auth| async move {
+            handlers::channel_stats(channel.as_str(), env)
+                .await
+                .map_err(problem::build)
+        });
+
+    let create_webhook = warp::path::end()
+        .and(warp::post())
+        .and(api_key_auth.clone())
+        .and(with_env.clone())
+        .and(with_limited_body(1024 * 16))
+        .and_then(|_auth_header, env, body: Vec<u8>| async move {
+            let req = serde_json::from_slice(body.as_slice()).map_err(|e| {
+                problem::build(RequestError::InvalidParameter {
+                    name: "body",
+                    reason: e.to_string(),
+                })
+            })?;
+            handlers::create_webhook(env, req)
+                .await
+                .map_err(problem::build)
+        });
+
+    let delete_webhook = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(api_key_auth.clone())
+        .and(with_env.clone())
+        .and_then(|webhook_id: String, _auth_header, env| async move {
+            handlers::delete_webhook(&webhook_id, env)
+                .await
+                .map_err(problem::build)
+        });
+
     let create_channel = warp::path::end()
         .and(warp::post())
         .and(api_key_auth)
@@ -127,16 +398,212 @@ pub fn webchannel(
                 .map_err(problem::build)
         });
 
+    let channels = warp::path("channels").and(
+        publish
+            .or(subscribe)
+            .or(multiplex)
+            .or(subscribe_events)
+            .or(poll)
+            .or(channel_stats)
+            .or(create_channel),
+    );
+    let patterns = warp::path("patterns").and(psubscribe);
+    let webhooks = warp::path("webhooks").and(create_webhook.or(delete_webhook));
+
     warp::path("webchannel")
         .and(warp::path("v1"))
-        .and(warp::path("channels"))
-        .and(publish.or(subscribe).or(create_channel))
+        .and(channels.or(patterns).or(webhooks))
+        .map(Reply::into_response)
+        // Boxed, as the futures of every route together overflow the stack in debug builds.
+        .boxed()
+}
+
+/// Pusher's HTTP API and WebSocket protocol, for pusher-js and Laravel Echo clients.
+pub fn pusher(environment: Environment) -> BoxedFilter<(warp::reply::Response,)> {
+    let enabled = environment.settings.pusher.enabled;
+    let deflate = deflate_offer(environment.settings.deflate.clone());
+    let upgrade = upgrade(environment.shutdown.clone());
+    let with_env = warp::any().map(move || environment.clone());
+
+    let trigger = warp::path!("apps" / String / "events")
+        .and(warp::post())
+        // Unsigned requests have no query, and are refused by the handler.
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(with_limited_body(MAX_MESSAGE_SIZE))
+        .and(with_env.clone())
+        .and_then(
+            |app_id: String, query: String, body: Vec<u8>, env| async move {
+                handlers::pusher_trigger(&app_id, &query, &body, env)
+                    .await
+                    .map_err(problem::build)
+            },
+        );
+
+    let connect = warp::path!("app" / String)
+        .and(upgrade)
+        .and(deflate)
+        .and(with_env)
+        .map(
+            |app_key: String, ws: warp::ws::Ws, deflate: Option<deflate::Offer>, env| {
+                let mut reply = ws
+                    .max_message_size(MAX_COMMAND_SIZE)
+                    .on_upgrade(move |websocket| async move {
+                        handlers::pusher(&app_key, env, websocket).await
+                    })
+                    .into_response();
+                if let Some(deflate) = deflate {
+                    deflate.accept(&mut reply);
+                }
+                reply
+            },
+        );
+
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .and(trigger.or(connect))
+        .map(Reply::into_response)
+        // Boxed like the webchannel routes.
+        .boxed()
 }
 
 fn channel_param() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
     warp::path::param::<String>()
 }
 
+fn check_channel_claim(
+    channel_id: &str,
+    claims: &biscuit::ClaimsSet<auth::Claims>,
+) -> Result<(), Rejection> {
+    if channel_id == claims.private.cid {
+        return Ok(());
+    }
+    debug!(
+        "Requested channel and claim mismatch: requested: {:?}, claim: {:?}",
+        channel_id, claims.private.cid
+    );
+    Err(problem::build(auth::AuthError::InvalidCredentials))
+}
+
+/// Parses a requested pattern, which must be within the token's pattern claim.
+fn check_pattern_claim(
+    pattern: &str,
+    claims: &biscuit::ClaimsSet<auth::Claims>,
+) -> Result<broker::ChannelPattern, Rejection> {
+    let pattern: broker::ChannelPattern = pattern.parse().map_err(problem::build)?;
+    let claim = claims
+        .private
+        .cpt
+        .as_deref()
+        .and_then(|claim| claim.parse::<broker::ChannelPattern>().ok());
+    match claim {
+        Some(claim) if pattern.within(&claim) => Ok(pattern),
+        _ => {
+            debug!(
+                "Requested pattern and claim mismatch: requested: {:?}, claim: {:?}",
+                pattern.to_string(),
+                claims.private.cpt
+            );
+            Err(problem::build(auth::AuthError::InvalidCredentials))
+        }
+    }
+}
+
+/// Matches requests accepting `text/event-stream`, as sent by `EventSource`.
+fn accepts_event_stream() -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::header::<String>("accept")
+        .and_then(|accept: String| async move {
+            if accept.contains("text/event-stream") {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Accepts WebSocket upgrades, until the server starts shutting down.
+fn upgrade(
+    shutdown: Shutdown,
+) -> impl Filter<Extract = (warp::ws::Ws,), Error = Rejection> + Clone {
+    warp::ws().and_then(move |ws| {
+        let draining = shutdown.is_draining();
+        async move {
+            if draining {
+                Err(problem::build(RequestError::ShuttingDown))
+            } else {
+                Ok(ws)
+            }
+        }
+    })
+}
+
+/// Negotiates permessage-deflate with clients offering it, on connections served by
+/// `server::run`.
+fn deflate_offer(
+    settings: settings::Deflate,
+) -> impl Filter<Extract = (Option<deflate::Offer>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("sec-websocket-extensions")
+        .and(warp::ext::optional::<deflate::Handle>())
+        .map(
+            move |offers: Option<String>, handle: Option<deflate::Handle>| {
+                if !settings.enabled {
+                    return None;
+                }
+                deflate::Offer::negotiate(&offers?, handle?, &settings)
+            },
+        )
+}
+
+/// Picks WebSocket frames from the query or subprotocol, with the subprotocol to echo back.
+fn frame_type(
+) -> impl Filter<Extract = (handlers::FrameType, Option<&'static str>), Error = Rejection> + Copy {
+    warp::query::<FramesQuery>()
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .map(|query: FramesQuery, protocols: Option<String>| {
+            // The client lists subprotocols by preference, the first we know is selected.
+            let selected = protocols
+                .unwrap_or_default()
+                .split(',')
+                .find_map(|protocol| match protocol.trim() {
+                    TEXT_SUBPROTOCOL => Some((handlers::FrameType::Text, TEXT_SUBPROTOCOL)),
+                    JSON_SUBPROTOCOL => Some((handlers::FrameType::Json, JSON_SUBPROTOCOL)),
+                    _ => None,
+                });
+            match selected {
+                Some((frames, subprotocol)) => (frames, Some(subprotocol)),
+                None => (query.frames, None),
+            }
+        })
+        .untuple_one()
+}
+
+/// Collects `x-header-<name>` request headers, to attach to a published message as `<name>`.
+fn message_headers() -> impl Filter<Extract = (BTreeMap<String, String>,), Error = Rejection> + Copy
+{
+    warp::header::headers_cloned().and_then(|headers: warp::http::HeaderMap| async move {
+        let mut attached = BTreeMap::new();
+        for (name, value) in &headers {
+            if let Some(name) = name.as_str().strip_prefix(MESSAGE_HEADER_PREFIX) {
+                let value = value.to_str().map_err(|_| {
+                    problem::build(RequestError::InvalidParameter {
+                        name: "x-header",
+                        reason: format!("Header {:?} must be visible ASCII", name),
+                    })
+                })?;
+                attached.insert(name.to_owned(), value.to_owned());
+            }
+        }
+        Ok::<_, Rejection>(attached)
+    })
+}
+
 async fn limited_body(
     mut stream: impl futures::Stream<Item = Result<impl warp::Buf, warp::Error>> + Unpin,
     max_bytes: &usize,
@@ -174,6 +641,13 @@ pub fn health() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone
         .and_then(handlers::health)
 }
 
+pub fn ready(shutdown: Shutdown) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("readyz")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and_then(move || handlers::ready(shutdown.clone()))
+}
+
 fn check_metrics_auth(settings: settings::Metrics, auth_header: Option<String>) -> bool {
     if !settings.auth_enabled {
         return true;
@@ -1,34 +1,182 @@
 use crate::{
     auth,
-    channel::{ChannelToken, CreateChannelRequest},
+    broker::{self, ChannelPattern, HistoryStart},
+    channel::{
+        ChannelToken, CreateChannelRequest, CreateWebhookRequest, Envelope, MultiplexCommand,
+        MultiplexReply, PollResponse, PolledMessage, WebhookCreated,
+    },
     environment::Environment,
+    error::RequestError,
+    keepalive::{Keepalive, Tick},
     metrics,
+    multiplex::Subscriptions,
+    outbox::{Closed, Outbox},
+    pusher::{self, ServerEvent, SubscriptionError},
+    shutdown::{self, Shutdown},
+    subscription::{recover, recover_pattern, Delivery},
 };
 use anyhow::Context;
+use bytes::Bytes;
 use chrono::{prelude::*, Duration};
-use futures::{
-    select,
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
-};
+use futures::{select, stream::SplitStream, FutureExt, SinkExt, StreamExt};
 use prometheus::{Encoder as PrometheusEncoder, TextEncoder};
-use redis_async::{client::pubsub::PubsubStream, resp::RespValue, resp_array};
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::convert::{Infallible, TryFrom};
-use tracing::{debug, error, trace, warn};
+use std::time::Duration as StdDuration;
+use tokio::sync::mpsc;
+use tracing::{debug, trace, warn};
 use warp::{
     http,
     ws::{Message, WebSocket},
     Reply,
 };
 
-fn make_channel_key(channel_id: &str) -> String {
-    format!("wc:channel:{}", channel_id)
+/// Response header carrying the ID of a message kept in channel history.
+const MESSAGE_ID_HEADER: &str = "x-message-id";
+/// Response header carrying how many subscribers received a published message.
+const RECEIVERS_HEADER: &str = "x-receivers";
+
+/// Text frame sent to clients that asked for it, after their subscription was recovered.
+const RESUBSCRIBED_NOTICE: &str = r#"{"event":"resubscribed"}"#;
+
+/// Appended to a channel ID to name its upstream companion, for messages from subscribers.
+const UPSTREAM_SUFFIX: &str = ":up";
+
+/// WebSocket close code for frames over the size limit.
+const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+/// WebSocket close code for clients sending faster than their messages are published.
+const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+
+/// Client frames waiting to be published upstream, per connection.
+const UPSTREAM_QUEUE_SIZE: usize = 64;
+/// Content types of messages from text and binary client frames.
+const TEXT_FRAME_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+const BINARY_FRAME_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Event name in envelopes of messages published without one.
+const DEFAULT_EVENT: &str = "message";
+
+/// Comments are sent this often on idle event streams, so proxies keep them open.
+const EVENT_STREAM_HEARTBEAT: StdDuration = StdDuration::from_secs(15);
+
+/// WebSocket frames used to deliver channel messages.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameType {
+    /// Every payload in a binary frame.
+    #[default]
+    Binary,
+    /// Text payloads in text frames, and others in binary frames.
+    Text,
+    /// Every message in a JSON envelope with its metadata, in a text frame.
+    Json,
+}
+
+/// Whether a content type declares text, so its UTF-8 payloads may be sent as text frames.
+fn is_text_content_type(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.starts_with("text/")
+        || essence.ends_with("/json")
+        || essence.ends_with("+json")
+        || essence.ends_with("/xml")
+        || essence.ends_with("+xml")
+        || essence == "application/javascript"
+        || essence == "application/x-www-form-urlencoded"
+        || content_type.contains("charset=")
+}
+
+fn is_json_content_type(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case("application/json")
+        || essence.to_ascii_lowercase().ends_with("+json")
+}
+
+/// Returns the payload as text, if it's valid UTF-8 and its content type doesn't say otherwise.
+fn payload_text(message: &broker::Message) -> Option<&str> {
+    let text = message
+        .content_type
+        .as_deref()
+        .is_none_or(is_text_content_type);
+    text.then(|| std::str::from_utf8(&message.payload).ok())
+        .flatten()
+}
+
+/// Wraps a channel message with its metadata, embedding JSON payloads as JSON.
+fn envelope(channel_id: &str, message: &broker::Message) -> Envelope {
+    let json = message
+        .content_type
+        .as_deref()
+        .is_some_and(is_json_content_type);
+    let (data, base64) = match payload_text(message) {
+        Some(text) if json => match serde_json::from_str(text) {
+            Ok(data) => (data, false),
+            Err(_) => (text.into(), false),
+        },
+        Some(text) => (text.into(), false),
+        None => (base64::encode(&message.payload).into(), true),
+    };
+    let mut headers = message.headers.clone();
+    if let Some(content_type) = &message.content_type {
+        headers.insert("content-type".to_owned(), content_type.clone());
+    }
+    Envelope {
+        id: message.id.map(|id| id.to_string()),
+        channel: channel_id.to_owned(),
+        event: message
+            .event
+            .clone()
+            .unwrap_or_else(|| DEFAULT_EVENT.to_owned()),
+        ts: message.timestamp,
+        headers,
+        data,
+        base64,
+    }
+}
+
+/// Builds the frame for a channel message, text only if asked for and the payload is text.
+///
+/// Messages without a content type are sent as text when they're valid UTF-8.
+fn channel_frame(channel_id: &str, message: broker::Message, frames: FrameType) -> Message {
+    match frames {
+        FrameType::Binary => Message::binary(message.payload.to_vec()),
+        FrameType::Text => match payload_text(&message) {
+            Some(text) => Message::text(text),
+            None => Message::binary(message.payload.to_vec()),
+        },
+        FrameType::Json => Message::text(
+            serde_json::to_string(&envelope(channel_id, &message))
+                .expect("Failed to serialize envelope"),
+        ),
+    }
+}
+
+/// How message payloads are written to event stream data.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EventEncoding {
+    /// UTF-8 payloads as-is, and others base64 encoded as `base64` events.
+    #[default]
+    Utf8,
+    /// Every payload base64 encoded.
+    Base64,
 }
 
 pub async fn health() -> Result<impl Reply, Infallible> {
     Ok("OK")
 }
 
+/// Fails once the server is shutting down, so load balancers stop sending it traffic.
+pub async fn ready(shutdown: Shutdown) -> Result<impl Reply, Infallible> {
+    let (body, status) = if shutdown.is_draining() {
+        ("Shutting down", http::StatusCode::SERVICE_UNAVAILABLE)
+    } else {
+        ("OK", http::StatusCode::OK)
+    };
+    Ok(warp::reply::with_status(body, status))
+}
+
 pub async fn metrics() -> anyhow::Result<impl Reply> {
     let mut buf = vec![];
     let encoder = TextEncoder::new();
@@ -37,60 +185,185 @@ pub async fn metrics() -> anyhow::Result<impl Reply> {
     Ok(output)
 }
 
+/// Timestamps and publishes a message, counting it.
+async fn publish_message(
+    channel_id: &str,
+    mut message: broker::Message,
+    env: &Environment,
+) -> anyhow::Result<broker::Published> {
+    let body_size = message.payload.len();
+    message.timestamp = Some(Utc::now().timestamp_millis() as u64);
+    let published = env
+        .broker
+        .publish(channel_id, message)
+        .await
+        .context("Failed to publish message")?;
+
+    metrics::MESSAGES_PUBLISHED.inc();
+    metrics::MESSAGES_PUBLISHED_BYTES.inc_by(u64::try_from(body_size).unwrap());
+    Ok(published)
+}
+
 pub async fn publish(
     channel_id: &str,
-    body: Vec<u8>,
+    message: broker::Message,
+    require_subscribers: bool,
     env: Environment,
 ) -> anyhow::Result<impl Reply> {
-    let connection = env
-        .redis_pool
-        .get()
-        .await
-        .context("Failed to get redis connection from pool")?;
+    if require_subscribers && !env.broker.counts_receivers() {
+        return Err(RequestError::InvalidParameter {
+            name: "require_subscribers",
+            reason: "The broker can't tell whether a channel has subscribers".to_owned(),
+        }
+        .into());
+    }
+    if require_subscribers {
+        // Checked first, so a refused message isn't kept in history and replayed on resume.
+        let stats = env
+            .broker
+            .stats(channel_id)
+            .await
+            .context("Failed to count subscribers")?;
+        if stats.nodes.unwrap_or(stats.subscribers) == 0 {
+            return Err(RequestError::NoSubscribers.into());
+        }
+    }
+    let published = publish_message(channel_id, message, &env).await?;
+    // Subscribers may have left since, which only loses the message if history didn't keep it.
+    if require_subscribers && published.receivers == Some(0) && published.id.is_none() {
+        return Err(RequestError::NoSubscribers.into());
+    }
 
-    let body_size = body.len();
-    let resp = resp_array!["PUBLISH", make_channel_key(&channel_id), body];
-    let _clients: RespValue = connection
-        .send(resp)
-        .await
-        .context("Failed to send publish command")?;
+    let mut response =
+        warp::reply::with_status(warp::reply(), http::StatusCode::NO_CONTENT).into_response();
+    if let Some(id) = published.id {
+        response.headers_mut().insert(
+            MESSAGE_ID_HEADER,
+            http::HeaderValue::from_str(&id.to_string())?,
+        );
+    }
+    if let Some(receivers) = published.receivers {
+        response
+            .headers_mut()
+            .insert(RECEIVERS_HEADER, http::HeaderValue::from(receivers));
+    }
+    Ok(response)
+}
 
-    metrics::MESSAGES_PUBLISHED.inc();
-    metrics::MESSAGES_PUBLISHED_BYTES.inc_by(u64::try_from(body_size).unwrap());
+fn handle_channel_message(
+    outbox: &Outbox,
+    channel_id: &str,
+    message: broker::Message,
+    frames: FrameType,
+) -> Result<(), Closed> {
+    outbox.deliver(channel_frame(channel_id, message, frames))
+}
 
-    Ok(warp::reply::with_status(
-        warp::reply(),
-        http::StatusCode::NO_CONTENT,
-    ))
+/// Publishes client frames to the channel's upstream companion, `{channel_id}:up`.
+///
+/// Frames are queued for a task publishing them in order, so the relay isn't held up.
+struct Upstream {
+    queue: mpsc::Sender<broker::Message>,
+    max_message_size: usize,
 }
 
-async fn handle_channel_message(
-    ws_tx: &mut SplitSink<warp::ws::WebSocket, warp::ws::Message>,
-    redis_result: Result<RespValue, redis_async::error::Error>,
-) -> anyhow::Result<()> {
-    let resp_value = redis_result.context("Error receiving channel message")?;
+impl Upstream {
+    fn new(env: &Environment, channel_id: &str) -> Option<Self> {
+        let settings = &env.settings.upstream;
+        if !settings.enabled {
+            return None;
+        }
+        let (queue, queued) = mpsc::channel(UPSTREAM_QUEUE_SIZE);
+        let channel_id = format!("{}{}", channel_id, UPSTREAM_SUFFIX);
+        tokio::spawn(publish_upstream(env.clone(), channel_id, queued));
+        Some(Self {
+            queue,
+            max_message_size: settings.max_message_size,
+        })
+    }
 
-    match resp_value {
-        RespValue::BulkString(v) => match ws_tx.send(warp::filters::ws::Message::binary(v)).await {
-            Ok(_) => metrics::MESSAGES_SENT.inc(),
-            Err(e) => {
-                warn!("Error sending websocket message: {:?}", e);
-                metrics::MESSAGE_SEND_ERRORS.inc();
-                return Err(anyhow::anyhow!(e));
-            }
-        },
-        _ => {
-            metrics::REDIS_SUBSCRIBE_UNEXPECTED_MESSAGE_TYPES.inc();
-            error!("Received unexpected redis type, ignoring");
+    /// Queues a text or binary frame, returning a close frame if the client must go.
+    fn publish(&self, message: Message) -> Option<Message> {
+        if !message.is_text() && !message.is_binary() {
+            return None;
         }
+        if message.as_bytes().len() > self.max_message_size {
+            debug!("Upstream message exceeds {} bytes", self.max_message_size);
+            return Some(Message::close_with(
+                CLOSE_MESSAGE_TOO_BIG,
+                "Message too big",
+            ));
+        }
+        let content_type = if message.is_text() {
+            TEXT_FRAME_CONTENT_TYPE
+        } else {
+            BINARY_FRAME_CONTENT_TYPE
+        };
+        let message = broker::Message {
+            content_type: Some(content_type.to_owned()),
+            ..broker::Message::new(message.into_bytes().into())
+        };
+        if self.queue.try_send(message).is_err() {
+            debug!("Upstream queue is full, closing");
+            return Some(Message::close_with(
+                CLOSE_TRY_AGAIN_LATER,
+                "Sending too fast",
+            ));
+        }
+        None
     }
-    Ok(())
 }
 
+/// Publishes a connection's upstream messages in order, until the connection is gone.
+async fn publish_upstream(
+    env: Environment,
+    channel_id: String,
+    mut queued: mpsc::Receiver<broker::Message>,
+) {
+    while let Some(message) = queued.recv().await {
+        if let Err(e) = publish_message(&channel_id, message, &env).await {
+            warn!("Failed publishing upstream to {:?}: {:?}", channel_id, e);
+        }
+    }
+}
+
+/// What a relay loop woke up for.
+enum Wake<T> {
+    Channel(Option<T>),
+    Client(Option<Result<Message, warp::Error>>),
+    Keepalive(Tick),
+    Shutdown,
+}
+
+/// Queues the close frame for a connection the server is going away from.
+fn handle_shutdown(outbox: &Outbox) {
+    debug!("Server shutting down, closing WebSocket connection");
+    let _ = outbox.send_control(shutdown::close_message());
+}
+
+/// Queues a due ping, or the close frame for a timed out connection, returning whether to go on.
+fn handle_tick(outbox: &Outbox, tick: Tick) -> bool {
+    match tick {
+        Tick::Ping(ping) => outbox.send_control(ping).is_ok(),
+        Tick::Close(close) => {
+            debug!("WebSocket connection timed out, closing");
+            let _ = outbox.send_control(close);
+            false
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn relay_messages(
-    ws_tx: &mut SplitSink<WebSocket, Message>,
+    channel_id: &str,
+    outbox: &Outbox,
     ws_rx: SplitStream<WebSocket>,
-    messages: PubsubStream,
+    messages: impl futures::Stream<Item = Delivery> + Unpin,
+    frames: FrameType,
+    resubscribed_notice: bool,
+    upstream: Option<Upstream>,
+    mut keepalive: Keepalive,
+    shutdown: &Shutdown,
 ) -> anyhow::Result<()> {
     // select macro requires these to be fused.
     let mut rx = ws_rx.fuse();
@@ -98,34 +371,68 @@ async fn relay_messages(
 
     loop {
         // Poll for client disconnects or pub/sub messages.
-        // Even though we don't use client messages, we must poll for disconnects.
+        // Even when client messages aren't sent upstream, we must poll for disconnects.
         let result = select! {
-            chan_msg = msgs.next() => Ok(chan_msg),
-            client_msg = rx.next() => Err(client_msg),
+            chan_msg = msgs.next() => Wake::Channel(chan_msg),
+            client_msg = rx.next() => Wake::Client(client_msg),
+            tick = keepalive.tick().fuse() => Wake::Keepalive(tick),
+            _ = shutdown.draining().fuse() => Wake::Shutdown,
         };
         match result {
-            Ok(chan_msg) => {
-                if let Some(redis_result) = chan_msg {
-                    if handle_channel_message(ws_tx, redis_result).await.is_err() {
+            Wake::Shutdown => {
+                handle_shutdown(outbox);
+                break;
+            }
+            Wake::Keepalive(tick) => {
+                if !handle_tick(outbox, tick) {
+                    break;
+                }
+            }
+            Wake::Channel(chan_msg) => match chan_msg {
+                Some(Delivery::Message(message)) => {
+                    keepalive.active();
+                    if handle_channel_message(outbox, channel_id, message, frames).is_err() {
                         break;
                     }
                 }
-            }
-            Err(client_rx_select) => {
-                if let Some(client_rx_result) = client_rx_select {
-                    match client_rx_result {
-                        Ok(_client_msg) => {
-                            metrics::WEBSOCKET_MESSAGES_RECEIVED.inc();
+                Some(Delivery::Resubscribed) => {
+                    if resubscribed_notice
+                        && outbox.send(Message::text(RESUBSCRIBED_NOTICE)).is_err()
+                    {
+                        break;
+                    }
+                }
+                None => {
+                    debug!("Channel subscription ended, closing");
+                    break;
+                }
+            },
+            Wake::Client(client_rx_select) => match client_rx_select {
+                Some(Ok(client_msg)) if client_msg.is_close() => break,
+                Some(Ok(client_msg)) if client_msg.is_pong() => keepalive.pong(),
+                // Pings are answered for us.
+                Some(Ok(client_msg)) if client_msg.is_ping() => (),
+                Some(Ok(client_msg)) => {
+                    metrics::WEBSOCKET_MESSAGES_RECEIVED.inc();
+                    keepalive.active();
+                    let upstream = match &upstream {
+                        Some(upstream) => upstream,
+                        None => {
                             debug!("Received client message, aborting");
                             break;
                         }
-                        Err(e) => {
-                            debug!("WebSocket connection error: {:?}", e);
-                            break;
-                        }
+                    };
+                    if let Some(close) = upstream.publish(client_msg) {
+                        let _ = outbox.send_control(close);
+                        break;
                     }
                 }
-            }
+                Some(Err(e)) => {
+                    debug!("WebSocket connection error: {:?}", e);
+                    break;
+                }
+                None => break,
+            },
         }
     }
     Ok(())
@@ -133,34 +440,234 @@ async fn relay_messages(
 
 pub async fn subscribe(
     channel_id: &str,
+    start: Option<HistoryStart>,
+    frames: FrameType,
+    resubscribed_notice: bool,
     env: Environment,
     websocket: WebSocket,
 ) -> anyhow::Result<()> {
     trace!("New subscriber on channel {:?}", channel_id);
+    let _connected = UserConnected::new();
+    let shutdown = env.shutdown.clone();
+    let _connection = shutdown.connection();
     let (mut ws_tx, ws_rx) = websocket.split();
 
-    let redis_addr = env.settings.redis.address;
-    let rd_client = match redis_async::client::pubsub_connect(redis_addr)
+    let messages = match env
+        .broker
+        .resume(channel_id, start)
         .await
-        .context("Failed connecting to redis")
+        .context("Failed subscribing to channel")
     {
-        Ok(conn) => {
-            metrics::REDIS_CONNECTIONS_CREATED
-                .with_label_values(&["false"])
-                .inc();
-            conn
-        }
+        Ok(stream) => stream,
         Err(e) => {
-            metrics::REDIS_CONNECTION_ERRORS.inc();
             let _ = ws_tx.close().await;
             return Err(e);
         }
     };
 
-    let messages = match rd_client
-        .subscribe(make_channel_key(&channel_id).as_str())
+    let upstream = Upstream::new(&env, channel_id);
+    let keepalive = Keepalive::new(&env.settings.websocket);
+    let outbox = Outbox::new(ws_tx, &env.settings.websocket);
+    let messages = Box::pin(recover(env, channel_id, messages));
+    let result = relay_messages(
+        channel_id,
+        &outbox,
+        ws_rx,
+        messages,
+        frames,
+        resubscribed_notice,
+        upstream,
+        keepalive,
+        &shutdown,
+    )
+    .await;
+    outbox.finish().await;
+    result
+}
+
+/// Subscribes a multiplexed connection to a channel, checking its token's claim.
+async fn subscribe_multiplexed(
+    env: &Environment,
+    subscriptions: &mut Subscriptions,
+    channel_id: &str,
+    token: &str,
+    start: Option<HistoryStart>,
+) -> anyhow::Result<()> {
+    let claims = env
+        .jwt
+        .decode(token)
+        .map_err(|_| auth::AuthError::InvalidCredentials)?;
+    // Pattern tokens have no channel ID.
+    if channel_id.is_empty() || claims.private.cid != channel_id {
+        debug!(
+            "Requested channel and claim mismatch: requested: {:?}, claim: {:?}",
+            channel_id, claims.private.cid
+        );
+        return Err(auth::AuthError::InvalidCredentials.into());
+    }
+    if subscriptions.contains(channel_id) {
+        return Err(anyhow::anyhow!("already subscribed"));
+    }
+    let max_channels = env.settings.multiplex.max_channels;
+    if subscriptions.len() >= max_channels {
+        return Err(anyhow::anyhow!(
+            "subscribed to the most channels allowed, {}",
+            max_channels
+        ));
+    }
+    let messages = env
+        .broker
+        .resume(channel_id, start)
+        .await
+        .context("Failed subscribing to channel")?;
+    subscriptions.insert(channel_id, recover(env.clone(), channel_id, messages));
+    Ok(())
+}
+
+async fn handle_command(
+    env: &Environment,
+    subscriptions: &mut Subscriptions,
+    command: MultiplexCommand,
+) -> MultiplexReply {
+    match command {
+        MultiplexCommand::Subscribe {
+            channel,
+            token,
+            last_event_id,
+            since,
+        } => {
+            trace!("New multiplexed subscriber on channel {:?}", channel);
+            let start = match (last_event_id, since) {
+                (Some(id), _) => match id.parse() {
+                    Ok(id) => Some(HistoryStart::After(id)),
+                    Err(e) => {
+                        return MultiplexReply::Error {
+                            channel: Some(channel),
+                            detail: format!("invalid lastEventId: {}", e),
+                        }
+                    }
+                },
+                (None, since) => since.map(HistoryStart::Since),
+            };
+            match subscribe_multiplexed(env, subscriptions, &channel, &token, start).await {
+                Ok(()) => MultiplexReply::Subscribed { channel },
+                Err(e) => MultiplexReply::Error {
+                    channel: Some(channel),
+                    detail: e.to_string(),
+                },
+            }
+        }
+        MultiplexCommand::Unsubscribe { channel } => {
+            if subscriptions.remove(&channel) {
+                MultiplexReply::Unsubscribed { channel }
+            } else {
+                MultiplexReply::Error {
+                    channel: Some(channel),
+                    detail: "not subscribed".to_owned(),
+                }
+            }
+        }
+    }
+}
+
+/// Relays any number of channels over one WebSocket, subscribed to by the client's commands.
+pub async fn multiplex(env: Environment, websocket: WebSocket) {
+    let _connected = UserConnected::new();
+    let shutdown = env.shutdown.clone();
+    let _connection = shutdown.connection();
+    let (ws_tx, ws_rx) = websocket.split();
+    let outbox = Outbox::new(ws_tx, &env.settings.websocket);
+    let mut rx = ws_rx.fuse();
+    let mut subscriptions = Subscriptions::default();
+    let mut keepalive = Keepalive::new(&env.settings.websocket);
+
+    loop {
+        let result = select! {
+            delivery = subscriptions.next().fuse() => Wake::Channel(Some(delivery)),
+            client_msg = rx.next() => Wake::Client(client_msg),
+            tick = keepalive.tick().fuse() => Wake::Keepalive(tick),
+            _ = shutdown.draining().fuse() => Wake::Shutdown,
+        };
+        let reply = match result {
+            Wake::Shutdown => {
+                handle_shutdown(&outbox);
+                break;
+            }
+            Wake::Keepalive(tick) => {
+                if handle_tick(&outbox, tick) {
+                    continue;
+                }
+                break;
+            }
+            Wake::Channel(Some((channel_id, Delivery::Message(message)))) => {
+                keepalive.active();
+                MultiplexReply::Message(envelope(&channel_id, &message))
+            }
+            Wake::Channel(Some((channel_id, Delivery::Resubscribed))) => {
+                MultiplexReply::Resubscribed {
+                    channel: channel_id,
+                }
+            }
+            Wake::Channel(None) => break,
+            Wake::Client(Some(Ok(client_msg))) if client_msg.is_close() => break,
+            Wake::Client(Some(Ok(client_msg))) if client_msg.is_pong() => {
+                keepalive.pong();
+                continue;
+            }
+            Wake::Client(Some(Ok(client_msg)))
+                if client_msg.is_text() || client_msg.is_binary() =>
+            {
+                metrics::WEBSOCKET_MESSAGES_RECEIVED.inc();
+                keepalive.active();
+                match serde_json::from_slice(client_msg.as_bytes()) {
+                    Ok(command) => handle_command(&env, &mut subscriptions, command).await,
+                    Err(e) => MultiplexReply::Error {
+                        channel: None,
+                        detail: format!("invalid command: {}", e),
+                    },
+                }
+            }
+            Wake::Client(Some(Ok(_))) => continue,
+            Wake::Client(Some(Err(e))) => {
+                debug!("WebSocket connection error: {:?}", e);
+                break;
+            }
+            Wake::Client(None) => break,
+        };
+
+        let frame =
+            Message::text(serde_json::to_string(&reply).expect("Failed to serialize reply"));
+        let queued = match reply {
+            MultiplexReply::Message(_) => outbox.deliver(frame),
+            _ => outbox.send(frame),
+        };
+        if queued.is_err() {
+            break;
+        }
+    }
+    outbox.finish().await;
+}
+
+/// Subscribes to every channel matching a pattern, sending messages in JSON envelopes.
+pub async fn psubscribe(
+    pattern: &ChannelPattern,
+    env: Environment,
+    websocket: WebSocket,
+) -> anyhow::Result<()> {
+    trace!(
+        "New subscriber on channel pattern {:?}",
+        pattern.to_string()
+    );
+    let _connected = UserConnected::new();
+    let shutdown = &env.shutdown;
+    let _connection = shutdown.connection();
+    let (mut ws_tx, ws_rx) = websocket.split();
+
+    let messages = match env
+        .broker
+        .psubscribe(pattern)
         .await
-        .context("Failed subscribing to redis channel")
+        .context("Failed subscribing to channel pattern")
     {
         Ok(stream) => stream,
         Err(e) => {
@@ -169,15 +676,213 @@ pub async fn subscribe(
         }
     };
 
-    let result = relay_messages(&mut ws_tx, ws_rx, messages).await;
-    let _ = ws_tx.close().await;
-    result
+    let outbox = Outbox::new(ws_tx, &env.settings.websocket);
+    let mut rx = ws_rx.fuse();
+    let mut messages = messages.fuse();
+    let mut keepalive = Keepalive::new(&env.settings.websocket);
+    loop {
+        let result = select! {
+            chan_msg = messages.next() => Wake::Channel(chan_msg),
+            client_msg = rx.next() => Wake::Client(client_msg),
+            tick = keepalive.tick().fuse() => Wake::Keepalive(tick),
+            _ = shutdown.draining().fuse() => Wake::Shutdown,
+        };
+        match result {
+            Wake::Shutdown => {
+                handle_shutdown(&outbox);
+                break;
+            }
+            Wake::Keepalive(tick) => {
+                if !handle_tick(&outbox, tick) {
+                    break;
+                }
+            }
+            Wake::Channel(Some(Ok((channel_id, message)))) => {
+                keepalive.active();
+                if handle_channel_message(&outbox, &channel_id, message, FrameType::Json).is_err() {
+                    break;
+                }
+            }
+            Wake::Channel(Some(Err(e))) => {
+                warn!("Channel pattern subscription error: {:?}", e);
+                break;
+            }
+            Wake::Channel(None) => {
+                debug!("Channel pattern subscription ended, closing");
+                break;
+            }
+            Wake::Client(Some(Ok(client_msg))) if client_msg.is_close() => break,
+            Wake::Client(Some(Ok(client_msg))) if client_msg.is_pong() => keepalive.pong(),
+            Wake::Client(Some(Ok(client_msg))) if client_msg.is_ping() => (),
+            Wake::Client(Some(Ok(_))) => {
+                debug!("Received client message, aborting");
+                break;
+            }
+            Wake::Client(Some(Err(e))) => {
+                debug!("WebSocket connection error: {:?}", e);
+                break;
+            }
+            Wake::Client(None) => break,
+        }
+    }
+    outbox.finish().await;
+    Ok(())
+}
+
+/// Encodes a payload as text, returning whether it was base64 encoded.
+fn encode_payload(payload: &[u8], encoding: EventEncoding) -> (String, bool) {
+    // Carriage returns end an event stream line too, so those payloads can't pass through.
+    let text = std::str::from_utf8(payload)
+        .ok()
+        .filter(|text| !text.contains('\r'));
+    match (encoding, text) {
+        (EventEncoding::Utf8, Some(text)) => (text.to_owned(), false),
+        _ => (base64::encode(payload), true),
+    }
+}
+
+fn channel_event(message: broker::Message, encoding: EventEncoding) -> warp::sse::Event {
+    let mut event = warp::sse::Event::default();
+    if let Some(id) = message.id {
+        event = event.id(id.to_string());
+    }
+    match encode_payload(&message.payload, encoding) {
+        (data, true) if encoding == EventEncoding::Utf8 => event.event("base64").data(data),
+        (data, _) => event.data(data),
+    }
+}
+
+/// Counts a connected user for as long as it's kept.
+struct UserConnected;
+
+impl UserConnected {
+    fn new() -> Self {
+        metrics::USERS_CONNECTED.inc();
+        Self
+    }
+}
+
+impl Drop for UserConnected {
+    fn drop(&mut self) {
+        metrics::USERS_CONNECTED.dec();
+    }
+}
+
+/// Subscribes to a channel as a `text/event-stream`, for clients that can't use WebSockets.
+pub async fn subscribe_events(
+    channel_id: &str,
+    start: Option<HistoryStart>,
+    encoding: EventEncoding,
+    env: Environment,
+) -> anyhow::Result<impl Reply> {
+    trace!("New event stream subscriber on channel {:?}", channel_id);
+    let messages = env
+        .broker
+        .resume(channel_id, start)
+        .await
+        .context("Failed subscribing to channel")?;
+
+    let connected = UserConnected::new();
+    // Ends the stream when shutting down, so the response completes.
+    let shutdown = env.shutdown.clone();
+    let messages =
+        recover(env, channel_id, messages).take_until(async move { shutdown.draining().await });
+    let events = messages.map(move |delivery| {
+        let _connected = &connected;
+        let event = match delivery {
+            Delivery::Message(message) => {
+                metrics::MESSAGES_SENT.inc();
+                channel_event(message, encoding)
+            }
+            Delivery::Resubscribed => warp::sse::Event::default()
+                .event("resubscribed")
+                .data(RESUBSCRIBED_NOTICE),
+        };
+        Ok::<_, Infallible>(event)
+    });
+    let events = warp::sse::keep_alive()
+        .interval(EVENT_STREAM_HEARTBEAT)
+        .stream(events);
+    Ok(warp::sse::reply(events))
+}
+
+/// Waits for channel messages after the cursor, or starts a new cursor without one.
+pub async fn poll(
+    channel_id: &str,
+    cursor: Option<String>,
+    start: Option<HistoryStart>,
+    encoding: EventEncoding,
+    env: Environment,
+) -> anyhow::Result<impl Reply> {
+    let (session, seq) = match cursor {
+        Some(cursor) => env.polls.find(&cursor, channel_id)?,
+        None => {
+            trace!("New poll subscriber on channel {:?}", channel_id);
+            let slot = env.polls.reserve(channel_id)?;
+            let messages = env
+                .broker
+                .resume(channel_id, start)
+                .await
+                .context("Failed subscribing to channel")?;
+            env.polls
+                .open(slot, recover(env.clone(), channel_id, messages))
+        }
+    };
+
+    let batch = env.polls.poll(&session, seq).await;
+    metrics::MESSAGES_SENT.inc_by(batch.messages.len() as u64);
+    let messages = batch
+        .messages
+        .into_iter()
+        .map(|message| {
+            let (data, base64) = encode_payload(&message.payload, encoding);
+            PolledMessage {
+                id: message.id.map(|id| id.to_string()),
+                content_type: message.content_type,
+                data,
+                base64,
+            }
+        })
+        .collect();
+    Ok(warp::reply::json(&PollResponse {
+        cursor: batch.cursor,
+        messages,
+        missed: batch.missed,
+    }))
+}
+
+pub async fn channel_stats(channel_id: &str, env: Environment) -> anyhow::Result<impl Reply> {
+    let stats = env.broker.stats(channel_id).await?;
+    Ok(warp::reply::json(&stats))
 }
 
 pub async fn create_channel(
     env: Environment,
     request: CreateChannelRequest,
 ) -> anyhow::Result<impl Reply> {
+    let expiry = Utc::now() + Duration::seconds(env.settings.channel.ttl as i64);
+    if let Some(pattern) = request.channel_pattern {
+        if request.channel_id.is_some() {
+            return Err(RequestError::InvalidParameter {
+                name: "channelPattern",
+                reason: "Expected either a channel ID or pattern".to_owned(),
+            }
+            .into());
+        }
+        let pattern: ChannelPattern = pattern.parse()?;
+        trace!("Creating token for channel pattern {:?}", pattern);
+        let claims = auth::Claims {
+            cid: String::new(),
+            cpt: Some(pattern.to_string()),
+        };
+        let token = env.jwt.encode(claims, expiry)?;
+        return Ok(warp::reply::json(&ChannelToken {
+            channel_id: None,
+            channel_pattern: Some(pattern.to_string()),
+            token,
+        }));
+    }
+
     let channel_id = match request.channel_id {
         Some(cid) => cid,
         None => nanoid::nanoid!(),
@@ -187,9 +892,340 @@ pub async fn create_channel(
     let token = env.jwt.encode(
         auth::Claims {
             cid: channel_id.clone(),
+            cpt: None,
         },
-        Utc::now() + Duration::seconds(env.settings.channel.ttl as i64),
+        expiry,
     )?;
 
-    Ok(warp::reply::json(&ChannelToken { channel_id, token }))
+    Ok(warp::reply::json(&ChannelToken {
+        channel_id: Some(channel_id),
+        channel_pattern: None,
+        token,
+    }))
+}
+
+pub async fn create_webhook(
+    env: Environment,
+    request: CreateWebhookRequest,
+) -> anyhow::Result<impl Reply> {
+    let invalid_url = |reason: String| RequestError::InvalidParameter {
+        name: "url",
+        reason,
+    };
+    let url: url::Url = request
+        .url
+        .parse()
+        .map_err(|e: url::ParseError| invalid_url(e.to_string()))?;
+    if url.scheme() != "https" && url.scheme() != "http" {
+        return Err(invalid_url("Expected an https or http URL".to_owned()).into());
+    }
+
+    let registration = match (request.channel_id, request.channel_pattern) {
+        (Some(channel_id), None) => {
+            let messages = env
+                .broker
+                .resume(&channel_id, None)
+                .await
+                .context("Failed subscribing to channel")?;
+            let deliveries =
+                recover(env.clone(), &channel_id, messages).filter_map(move |delivery| {
+                    let channel_id = channel_id.clone();
+                    async move {
+                        match delivery {
+                            Delivery::Message(message) => Some((channel_id, message)),
+                            Delivery::Resubscribed => None,
+                        }
+                    }
+                });
+            env.webhooks.register(url, deliveries)
+        }
+        (None, Some(pattern)) => {
+            let pattern: ChannelPattern = pattern.parse()?;
+            let messages = env.broker.psubscribe(&pattern).await?;
+            let deliveries =
+                recover_pattern(env.clone(), pattern, messages).filter_map(|delivery| async move {
+                    match delivery {
+                        Delivery::Message(message) => Some(message),
+                        Delivery::Resubscribed => None,
+                    }
+                });
+            env.webhooks.register(url, deliveries)
+        }
+        _ => {
+            return Err(RequestError::InvalidParameter {
+                name: "channelId",
+                reason: "Expected either a channel ID or pattern".to_owned(),
+            }
+            .into())
+        }
+    };
+    trace!("Registered webhook {:?}", registration.id);
+
+    Ok(warp::reply::json(&WebhookCreated {
+        webhook_id: registration.id,
+        secret: registration.secret,
+    }))
+}
+
+pub async fn delete_webhook(webhook_id: &str, env: Environment) -> anyhow::Result<impl Reply> {
+    if !env.webhooks.remove(webhook_id) {
+        return Err(RequestError::WebhookNotFound.into());
+    }
+    trace!("Removed webhook {:?}", webhook_id);
+    Ok(http::StatusCode::NO_CONTENT)
+}
+
+/// Triggers an event on each of its channels, through Pusher's HTTP API.
+pub async fn pusher_trigger(
+    app_id: &str,
+    query: &str,
+    body: &[u8],
+    env: Environment,
+) -> anyhow::Result<impl Reply> {
+    let settings = &env.settings.pusher;
+    if app_id != settings.app_id {
+        return Err(auth::AuthError::InvalidCredentials.into());
+    }
+    let path = format!("/apps/{}/events", app_id);
+    pusher::verify_request(settings, "POST", &path, query, body)?;
+
+    let request: pusher::TriggerRequest =
+        serde_json::from_slice(body).map_err(|e| RequestError::InvalidParameter {
+            name: "body",
+            reason: e.to_string(),
+        })?;
+    let invalid_channels = |reason: String| RequestError::InvalidParameter {
+        name: "channels",
+        reason,
+    };
+    let channels = match (request.channel, request.channels) {
+        (Some(channel), None) => vec![channel],
+        (None, Some(channels)) => channels,
+        _ => {
+            return Err(invalid_channels("Expected either a channel or channels".to_owned()).into())
+        }
+    };
+    if channels.is_empty() || channels.len() > pusher::MAX_EVENT_CHANNELS {
+        return Err(invalid_channels(format!(
+            "Expected from 1 to {} channels",
+            pusher::MAX_EVENT_CHANNELS
+        ))
+        .into());
+    }
+    if let Some(channel) = channels.iter().find(|c| !pusher::valid_channel_name(c)) {
+        return Err(invalid_channels(format!("Invalid channel name {:?}", channel)).into());
+    }
+
+    let mut headers = BTreeMap::new();
+    if let Some(socket_id) = request.socket_id {
+        headers.insert(pusher::SOCKET_ID_HEADER.to_owned(), socket_id);
+    }
+    let payload = Bytes::from(request.data);
+    for channel in &channels {
+        let message = broker::Message {
+            event: Some(request.name.clone()),
+            headers: headers.clone(),
+            ..broker::Message::new(payload.clone())
+        };
+        publish_message(&pusher::channel_id(channel), message, &env).await?;
+    }
+    Ok(warp::reply::json(&serde_json::json!({})))
+}
+
+/// Subscribes a Pusher connection to a channel, checking private channels' auth.
+///
+/// Subscriptions are kept under the Pusher channel's name, relaying its webchannel channel.
+async fn subscribe_pusher(
+    env: &Environment,
+    subscriptions: &mut Subscriptions,
+    socket_id: &str,
+    subscribe: &pusher::Subscribe,
+) -> Result<(), SubscriptionError> {
+    let channel_id = subscribe.channel.as_str();
+    if !pusher::valid_channel_name(channel_id) {
+        return Err(SubscriptionError::invalid("Invalid channel name"));
+    }
+    if channel_id.starts_with("presence-") {
+        return Err(SubscriptionError::invalid(
+            "Presence channels are not supported",
+        ));
+    }
+    let settings = &env.settings.pusher;
+    if channel_id.starts_with("private-")
+        && !pusher::verify_channel_auth(settings, socket_id, channel_id, subscribe.auth.as_deref())
+    {
+        return Err(SubscriptionError::unauthorized());
+    }
+    if subscriptions.contains(channel_id) {
+        return Ok(());
+    }
+    let max_channels = env.settings.multiplex.max_channels;
+    if subscriptions.len() >= max_channels {
+        return Err(SubscriptionError::invalid(format!(
+            "Subscribed to the most channels allowed, {}",
+            max_channels
+        )));
+    }
+    let broker_channel_id = pusher::channel_id(channel_id);
+    let messages = env
+        .broker
+        .resume(&broker_channel_id, None)
+        .await
+        .map_err(|e| {
+            warn!(
+                "Failed subscribing to channel {:?}: {:?}",
+                broker_channel_id, e
+            );
+            SubscriptionError {
+                status: 500,
+                error: "Failed subscribing to channel".to_owned(),
+            }
+        })?;
+    subscriptions.insert(
+        channel_id,
+        recover(env.clone(), &broker_channel_id, messages),
+    );
+    Ok(())
+}
+
+/// Handles an event from a Pusher client, returning the reply to send.
+async fn handle_pusher_event(
+    env: &Environment,
+    subscriptions: &mut Subscriptions,
+    socket_id: &str,
+    event: pusher::ClientEvent,
+) -> Option<ServerEvent> {
+    match event.event.as_str() {
+        "pusher:ping" => Some(ServerEvent::pong()),
+        "pusher:subscribe" => {
+            let subscribe: pusher::Subscribe = match serde_json::from_value(event.data) {
+                Ok(subscribe) => subscribe,
+                Err(e) => {
+                    return Some(ServerEvent::error(
+                        None,
+                        format!("Invalid subscribe: {}", e),
+                    ))
+                }
+            };
+            trace!("New Pusher subscriber on channel {:?}", subscribe.channel);
+            Some(
+                match subscribe_pusher(env, subscriptions, socket_id, &subscribe).await {
+                    Ok(()) => ServerEvent::subscription_succeeded(&subscribe.channel),
+                    Err(e) => ServerEvent::subscription_error(&subscribe.channel, e),
+                },
+            )
+        }
+        "pusher:unsubscribe" => {
+            if let Ok(unsubscribe) = serde_json::from_value::<pusher::Unsubscribe>(event.data) {
+                subscriptions.remove(&unsubscribe.channel);
+            }
+            None
+        }
+        name if name.starts_with("client-") => {
+            Some(ServerEvent::error(None, "Client events are not supported"))
+        }
+        name => {
+            debug!("Ignoring unknown Pusher event {:?}", name);
+            None
+        }
+    }
+}
+
+/// Speaks the Pusher protocol, relaying the channels a pusher-js client subscribes to.
+pub async fn pusher(app_key: &str, env: Environment, websocket: WebSocket) {
+    let _connected = UserConnected::new();
+    let shutdown = env.shutdown.clone();
+    let _connection = shutdown.connection();
+    let (ws_tx, ws_rx) = websocket.split();
+    let outbox = Outbox::new(ws_tx, &env.settings.websocket);
+    let settings = &env.settings.pusher;
+
+    if app_key != settings.key {
+        debug!("Pusher client connected with unknown key {:?}", app_key);
+        let error = ServerEvent::error(
+            Some(pusher::CLOSE_APP_NOT_FOUND),
+            format!("App key {} not in this cluster", app_key),
+        );
+        let _ = outbox.send(error.to_message());
+        let _ = outbox.send_control(Message::close_with(
+            pusher::CLOSE_APP_NOT_FOUND,
+            "App key not found",
+        ));
+        outbox.finish().await;
+        return;
+    }
+
+    let socket_id = pusher::socket_id();
+    let established = ServerEvent::connection_established(&socket_id, settings.activity_timeout);
+    let mut rx = ws_rx.fuse();
+    let mut subscriptions = Subscriptions::default();
+    let mut keepalive = Keepalive::new(&env.settings.websocket);
+    let mut reply = Some(established);
+
+    loop {
+        if let Some(event) = reply.take() {
+            if outbox.send(event.to_message()).is_err() {
+                break;
+            }
+        }
+        let result = select! {
+            delivery = subscriptions.next().fuse() => Wake::Channel(Some(delivery)),
+            client_msg = rx.next() => Wake::Client(client_msg),
+            tick = keepalive.tick().fuse() => Wake::Keepalive(tick),
+            _ = shutdown.draining().fuse() => Wake::Shutdown,
+        };
+        reply = match result {
+            Wake::Shutdown => {
+                handle_shutdown(&outbox);
+                break;
+            }
+            Wake::Keepalive(tick) => {
+                if handle_tick(&outbox, tick) {
+                    continue;
+                }
+                break;
+            }
+            Wake::Channel(Some((channel_id, Delivery::Message(message)))) => {
+                keepalive.active();
+                // Events triggered with a socket ID skip that connection.
+                if message.headers.get(pusher::SOCKET_ID_HEADER) == Some(&socket_id) {
+                    continue;
+                }
+                match ServerEvent::message(&channel_id, &message) {
+                    Some(event) => {
+                        if outbox.deliver(event.to_message()).is_err() {
+                            break;
+                        }
+                    }
+                    None => debug!("Skipping binary message on channel {:?}", channel_id),
+                }
+                continue;
+            }
+            // Pusher has no way to tell clients messages may have been missed.
+            Wake::Channel(Some((_, Delivery::Resubscribed))) => continue,
+            Wake::Channel(None) => break,
+            Wake::Client(Some(Ok(client_msg))) if client_msg.is_close() => break,
+            Wake::Client(Some(Ok(client_msg))) if client_msg.is_pong() => {
+                keepalive.pong();
+                continue;
+            }
+            Wake::Client(Some(Ok(client_msg))) if client_msg.is_text() => {
+                metrics::WEBSOCKET_MESSAGES_RECEIVED.inc();
+                keepalive.active();
+                match serde_json::from_slice(client_msg.as_bytes()) {
+                    Ok(event) => {
+                        handle_pusher_event(&env, &mut subscriptions, &socket_id, event).await
+                    }
+                    Err(e) => Some(ServerEvent::error(None, format!("Invalid event: {}", e))),
+                }
+            }
+            Wake::Client(Some(Ok(_))) => continue,
+            Wake::Client(Some(Err(e))) => {
+                debug!("WebSocket connection error: {:?}", e);
+                break;
+            }
+            Wake::Client(None) => break,
+        };
+    }
+    outbox.finish().await;
 }
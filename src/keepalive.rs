@@ -0,0 +1,93 @@
+use crate::{metrics, settings};
+use std::time::Duration;
+use tokio::time::Instant;
+use warp::ws::Message;
+
+/// WebSocket close code for clients that stopped answering pings, as the peer has gone away
+/// rather than broken the protocol.
+const CLOSE_GOING_AWAY: u16 = 1001;
+/// WebSocket close code for connections closed after going idle.
+const CLOSE_NORMAL: u16 = 1000;
+
+pub enum Tick {
+    /// Time to send this ping.
+    Ping(Message),
+    /// Time to close the connection with this frame.
+    Close(Message),
+}
+
+/// Pings a WebSocket client, and decides when it's unresponsive or idle.
+pub struct Keepalive {
+    ping_interval: Option<Duration>,
+    pong_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    last_ping: Instant,
+    /// When the ping still awaiting a pong was sent.
+    awaiting_pong: Option<Instant>,
+    last_active: Instant,
+}
+
+fn enabled(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+impl Keepalive {
+    pub fn new(settings: &settings::WebSocket) -> Self {
+        let now = Instant::now();
+        Self {
+            ping_interval: enabled(settings.ping_interval),
+            pong_timeout: Duration::from_secs(settings.pong_timeout),
+            idle_timeout: enabled(settings.idle_timeout),
+            last_ping: now,
+            awaiting_pong: None,
+            last_active: now,
+        }
+    }
+
+    /// Records a message sent or received, which keeps the connection from going idle.
+    pub fn active(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    pub fn pong(&mut self) {
+        self.awaiting_pong = None;
+    }
+
+    /// Waits until a ping is due, or the connection must be closed.
+    ///
+    /// Safe to cancel, as the deadlines are worked out again on each call.
+    pub async fn tick(&mut self) -> Tick {
+        let pong_deadline = self.awaiting_pong.map(|sent| sent + self.pong_timeout);
+        let idle_deadline = self.idle_timeout.map(|timeout| self.last_active + timeout);
+        // The next ping waits for the last one's pong.
+        let next_ping = match self.awaiting_pong {
+            Some(_) => None,
+            None => self.ping_interval.map(|interval| self.last_ping + interval),
+        };
+        match [pong_deadline, idle_deadline, next_ping]
+            .iter()
+            .flatten()
+            .min()
+        {
+            Some(deadline) => tokio::time::sleep_until(*deadline).await,
+            None => futures::future::pending().await,
+        }
+
+        let now = Instant::now();
+        if pong_deadline.is_some_and(|deadline| now >= deadline) {
+            metrics::WEBSOCKET_TIMEOUTS
+                .with_label_values(&["pong"])
+                .inc();
+            return Tick::Close(Message::close_with(CLOSE_GOING_AWAY, "Pong timeout"));
+        }
+        if idle_deadline.is_some_and(|deadline| now >= deadline) {
+            metrics::WEBSOCKET_TIMEOUTS
+                .with_label_values(&["idle"])
+                .inc();
+            return Tick::Close(Message::close_with(CLOSE_NORMAL, "Idle timeout"));
+        }
+        self.last_ping = now;
+        self.awaiting_pong = Some(now);
+        Tick::Ping(Message::ping(Vec::new()))
+    }
+}
@@ -7,13 +7,29 @@ extern crate lazy_static;
 extern crate prometheus;
 
 pub(crate) mod auth;
+pub mod broker;
 pub(crate) mod channel;
+pub(crate) mod cluster;
+pub(crate) mod connection;
+pub(crate) mod connector;
+pub(crate) mod deflate;
 pub mod environment;
 pub(crate) mod error;
 pub mod filters;
 pub(crate) mod handlers;
 pub(crate) mod jwt;
+pub(crate) mod keepalive;
 pub mod metrics;
+pub(crate) mod multiplex;
+pub(crate) mod outbox;
+pub(crate) mod poll;
 pub(crate) mod pool;
 pub mod problem;
+pub(crate) mod pubsub;
+pub(crate) mod pusher;
+pub mod server;
 pub mod settings;
+pub mod shutdown;
+pub(crate) mod subscription;
+pub(crate) mod tls;
+pub(crate) mod webhook;
@@ -1,4 +1,8 @@
-use prometheus::{opts, HistogramVec, IntCounter, IntCounterVec, IntGauge};
+use prometheus::{opts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether subscribers are also counted by channel, set once from `metrics.channel_subscribers`.
+static BY_CHANNEL: AtomicBool = AtomicBool::new(false);
 
 lazy_static! {
     pub static ref MESSAGES_PUBLISHED: IntCounter = register_int_counter!(
@@ -31,11 +35,78 @@ lazy_static! {
         "Count of errors encountered while sending to clients."
     )
     .unwrap();
+    pub static ref RESUBSCRIPTIONS: IntCounter = register_int_counter!(
+        "webchannel_resubscriptions_total",
+        "Total number of subscriptions recovered after the broker ended them."
+    )
+    .unwrap();
+    pub static ref WEBSOCKET_TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "webchannel_websocket_timeouts_total",
+            "Total number of websocket connections closed for missing a pong, or going idle."
+        ),
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref WEBSOCKET_QUEUED_MESSAGES: IntGauge = register_int_gauge!(
+        "webchannel_websocket_queued_messages",
+        "Count of messages queued to be sent over websockets."
+    )
+    .unwrap();
+    pub static ref MESSAGES_DROPPED: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "webchannel_messages_dropped_total",
+            "Total number of messages dropped for websockets with full send queues."
+        ),
+        &["policy"]
+    )
+    .unwrap();
+    pub static ref WEBSOCKET_UNCOMPRESSED_BYTES: IntCounter = register_int_counter!(
+        "webchannel_websocket_uncompressed_bytes_total",
+        "Total bytes of websocket messages before compression."
+    )
+    .unwrap();
+    pub static ref WEBSOCKET_COMPRESSED_BYTES: IntCounter = register_int_counter!(
+        "webchannel_websocket_compressed_bytes_total",
+        "Total bytes of websocket messages after compression."
+    )
+    .unwrap();
+    pub static ref WEBHOOK_DELIVERIES: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "webchannel_webhook_deliveries_total",
+            "Total number of messages delivered to webhooks, given up on, or dropped from a full queue."
+        ),
+        &["result"]
+    )
+    .unwrap();
+    pub static ref WEBHOOK_RETRIES: IntCounter = register_int_counter!(
+        "webchannel_webhook_retries_total",
+        "Total number of webhook deliveries retried after failing."
+    )
+    .unwrap();
     pub static ref USERS_CONNECTED: IntGauge = register_int_gauge!(
         "webchannel_users_connected",
         "Count of users currently connected to websockets."
     )
     .unwrap();
+    pub static ref CHANNEL_SUBSCRIBERS: IntGauge = register_int_gauge!(
+        "webchannel_channel_subscribers",
+        "Count of channel and pattern subscriptions on this node."
+    )
+    .unwrap();
+    pub static ref CHANNEL_LOCAL_SUBSCRIBERS: IntGaugeVec = register_int_gauge_vec!(
+        opts!(
+            "webchannel_channel_local_subscribers",
+            "Count of subscribers connected to this node, by channel or pattern."
+        ),
+        &["channel"]
+    )
+    .unwrap();
+    pub static ref CHANNELS_SUBSCRIBED: IntGauge = register_int_gauge!(
+        "webchannel_channels_subscribed",
+        "Count of channels and patterns with subscribers on this node."
+    )
+    .unwrap();
     pub static ref REDIS_CONNECTIONS_CREATED: IntCounterVec = register_int_counter_vec!(
         opts!(
             "webchannel_redis_connections_created_total",
@@ -49,6 +120,24 @@ lazy_static! {
         "Total errors encountered while creating a connection."
     )
     .unwrap();
+    pub static ref REDIS_PRIMARY_DISCOVERIES: IntCounter = register_int_counter!(
+        "webchannel_redis_primary_discoveries_total",
+        "Total number of times the redis primary was discovered through Sentinel."
+    )
+    .unwrap();
+    pub static ref REDIS_CLUSTER_REDIRECTS: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "webchannel_redis_cluster_redirects_total",
+            "Total number of MOVED and ASK redirects followed in a redis cluster."
+        ),
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref REDIS_CLUSTER_SLOT_REFRESHES: IntCounter = register_int_counter!(
+        "webchannel_redis_cluster_slot_refreshes_total",
+        "Total number of times the redis cluster slot map was loaded."
+    )
+    .unwrap();
     pub static ref REDIS_PUBLISH_ERRORS: IntCounter = register_int_counter!(
         "webchannel_redis_publish_errors_total",
         "Total errors encountered while publishing a message."
@@ -83,13 +172,16 @@ lazy_static! {
 
 // Known path segments. Just a simple way of naming handlers for metrics while
 // avoiding cardinality issues.
-static METRIC_PATH_SEGMENTS: [&str; 8] = [
+static METRIC_PATH_SEGMENTS: [&str; 11] = [
     "",
     "webchannel",
     "v1",
     "channels",
+    "patterns",
     "publish",
     "subscribe",
+    "stats",
+    "poll",
     "healthz",
     "metrics",
 ];
@@ -116,3 +208,28 @@ pub fn warp_log_metrics(info: warp::log::Info) {
         .with_label_values(&[endpoint.as_str(), status.as_str()])
         .inc();
 }
+
+/// Counts subscribers by channel too, which makes a series for each subscribed channel.
+pub fn count_subscribers_by_channel(enabled: bool) {
+    BY_CHANNEL.store(enabled, Ordering::Relaxed);
+}
+
+pub fn subscriber_added(channel_id: &str) {
+    CHANNEL_SUBSCRIBERS.inc();
+    if BY_CHANNEL.load(Ordering::Relaxed) {
+        CHANNEL_LOCAL_SUBSCRIBERS
+            .with_label_values(&[channel_id])
+            .inc();
+    }
+}
+
+pub fn subscriber_removed(channel_id: &str) {
+    CHANNEL_SUBSCRIBERS.dec();
+    if BY_CHANNEL.load(Ordering::Relaxed) {
+        let gauge = CHANNEL_LOCAL_SUBSCRIBERS.with_label_values(&[channel_id]);
+        gauge.dec();
+        if gauge.get() <= 0 {
+            let _ = CHANNEL_LOCAL_SUBSCRIBERS.remove_label_values(&[channel_id]);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use crate::subscription::Delivery;
+use futures::future::{AbortHandle, Abortable};
+use futures::stream::{BoxStream, SelectAll};
+use futures::{Stream, StreamExt};
+use std::collections::HashMap;
+
+type Deliveries = Abortable<BoxStream<'static, (String, Delivery)>>;
+
+/// The channels subscribed to on a multiplexed connection, merged into a single stream.
+#[derive(Default)]
+pub struct Subscriptions {
+    deliveries: SelectAll<Deliveries>,
+    handles: HashMap<String, AbortHandle>,
+}
+
+impl Subscriptions {
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn contains(&self, channel_id: &str) -> bool {
+        self.handles.contains_key(channel_id)
+    }
+
+    pub fn insert(
+        &mut self,
+        channel_id: &str,
+        deliveries: impl Stream<Item = Delivery> + Send + 'static,
+    ) {
+        let tag = channel_id.to_owned();
+        let deliveries = deliveries
+            .map(move |delivery| (tag.clone(), delivery))
+            .boxed();
+        let (handle, registration) = AbortHandle::new_pair();
+        self.deliveries
+            .push(Abortable::new(deliveries, registration));
+        if let Some(replaced) = self.handles.insert(channel_id.to_owned(), handle) {
+            replaced.abort();
+        }
+    }
+
+    /// Ends a channel's subscription, returning whether there was one.
+    pub fn remove(&mut self, channel_id: &str) -> bool {
+        match self.handles.remove(channel_id) {
+            Some(handle) => {
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Waits for the next delivery on any channel, tagged with its channel ID.
+    ///
+    /// Waits forever without subscriptions, so it can be selected on until one is made.
+    pub async fn next(&mut self) -> (String, Delivery) {
+        loop {
+            match self.deliveries.next().await {
+                Some(delivery) => return delivery,
+                None => futures::future::pending::<()>().await,
+            }
+        }
+    }
+}
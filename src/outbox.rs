@@ -0,0 +1,240 @@
+use crate::{
+    metrics,
+    settings::{self, QueuePolicy},
+};
+use futures::{stream::SplitSink, SinkExt};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+use warp::ws::{Message, WebSocket};
+
+/// WebSocket close code for clients disconnected for not keeping up.
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+/// How long closing waits for queued frames to be written, before dropping the socket.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The connection is closing, so nothing more can be sent.
+#[derive(Debug)]
+pub struct Closed;
+
+struct Queued {
+    message: Message,
+    /// Pings and close frames, which are never dropped or counted against the bound.
+    control: bool,
+    /// Channel messages, counted as sent once written.
+    delivery: bool,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Queued>,
+    /// Frames in the queue counted against the bound.
+    data_frames: usize,
+    closed: bool,
+}
+
+impl State {
+    fn push(&mut self, queued: Queued) {
+        if !queued.control {
+            self.data_frames += 1;
+        }
+        metrics::WEBSOCKET_QUEUED_MESSAGES.inc();
+        self.queue.push_back(queued);
+    }
+
+    fn pop(&mut self) -> Option<Queued> {
+        let queued = self.queue.pop_front()?;
+        if !queued.control {
+            self.data_frames -= 1;
+        }
+        metrics::WEBSOCKET_QUEUED_MESSAGES.dec();
+        Some(queued)
+    }
+
+    /// Drops the oldest frame counted against the bound.
+    fn drop_oldest(&mut self) {
+        if let Some(i) = self.queue.iter().position(|queued| !queued.control) {
+            self.queue.remove(i);
+            self.data_frames -= 1;
+            metrics::WEBSOCKET_QUEUED_MESSAGES.dec();
+        }
+    }
+
+    /// Empties the queue, returning how many frames counted against the bound were dropped.
+    fn clear(&mut self) -> usize {
+        let dropped = self.data_frames;
+        metrics::WEBSOCKET_QUEUED_MESSAGES.sub(self.queue.len() as i64);
+        self.queue.clear();
+        self.data_frames = 0;
+        dropped
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    writable: Notify,
+}
+
+/// A bounded queue of frames for a WebSocket client, written by its own task so a slow
+/// client doesn't hold up its subscriptions.
+pub struct Outbox {
+    shared: Arc<Shared>,
+    capacity: usize,
+    policy: QueuePolicy,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Outbox {
+    pub fn new(ws_tx: SplitSink<WebSocket, Message>, settings: &settings::WebSocket) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            writable: Notify::new(),
+        });
+        let writer = tokio::spawn(write(shared.clone(), ws_tx));
+        Self {
+            shared,
+            capacity: settings.send_queue_size,
+            policy: settings.send_queue_policy,
+            writer: Some(writer),
+        }
+    }
+
+    /// Queues a channel message.
+    pub fn deliver(&self, message: Message) -> Result<(), Closed> {
+        self.push(message, true)
+    }
+
+    /// Queues a frame that isn't a channel message, such as a notice or reply.
+    pub fn send(&self, message: Message) -> Result<(), Closed> {
+        self.push(message, false)
+    }
+
+    /// Queues a ping or close frame, which is sent even when the queue is full.
+    pub fn send_control(&self, message: Message) -> Result<(), Closed> {
+        let mut state = self.shared.state.lock();
+        if state.closed {
+            return Err(Closed);
+        }
+        if message.is_close() {
+            state.closed = true;
+        }
+        state.push(Queued {
+            message,
+            control: true,
+            delivery: false,
+        });
+        self.shared.writable.notify_one();
+        Ok(())
+    }
+
+    /// Closes the connection once the queued frames are written.
+    pub fn close(&self) {
+        self.shared.state.lock().closed = true;
+        self.shared.writable.notify_one();
+    }
+
+    /// Closes the connection, waiting until the queued frames are written, or dropping the
+    /// socket if the client doesn't take them in time.
+    pub async fn finish(mut self) {
+        self.close();
+        if let Some(mut writer) = self.writer.take() {
+            if tokio::time::timeout(FINISH_TIMEOUT, &mut writer)
+                .await
+                .is_err()
+            {
+                debug!("Timed out writing queued frames, dropping the connection");
+                writer.abort();
+                self.shared.state.lock().clear();
+            }
+        }
+    }
+
+    fn push(&self, message: Message, delivery: bool) -> Result<(), Closed> {
+        let mut state = self.shared.state.lock();
+        if state.closed {
+            return Err(Closed);
+        }
+        if state.data_frames >= self.capacity {
+            match self.policy {
+                QueuePolicy::DropOldest => {
+                    state.drop_oldest();
+                    metrics::MESSAGES_DROPPED
+                        .with_label_values(&["drop_oldest"])
+                        .inc();
+                }
+                QueuePolicy::DropNewest => {
+                    metrics::MESSAGES_DROPPED
+                        .with_label_values(&["drop_newest"])
+                        .inc();
+                    return Ok(());
+                }
+                QueuePolicy::Disconnect => {
+                    debug!("WebSocket send queue full, disconnecting");
+                    let dropped = state.clear() + 1;
+                    metrics::MESSAGES_DROPPED
+                        .with_label_values(&["disconnect"])
+                        .inc_by(dropped as u64);
+                    state.push(Queued {
+                        message: Message::close_with(CLOSE_POLICY_VIOLATION, "Send queue full"),
+                        control: true,
+                        delivery: false,
+                    });
+                    state.closed = true;
+                    self.shared.writable.notify_one();
+                    return Err(Closed);
+                }
+            }
+        }
+        state.push(Queued {
+            message,
+            control: false,
+            delivery,
+        });
+        self.shared.writable.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Writes queued frames until the outbox is closed and drained, or the client goes away.
+async fn write(shared: Arc<Shared>, mut ws_tx: SplitSink<WebSocket, Message>) {
+    loop {
+        let next = {
+            let mut state = shared.state.lock();
+            match state.pop() {
+                Some(queued) => Some(queued),
+                None if state.closed => break,
+                None => None,
+            }
+        };
+        let queued = match next {
+            Some(queued) => queued,
+            None => {
+                shared.writable.notified().await;
+                continue;
+            }
+        };
+        match ws_tx.send(queued.message).await {
+            Ok(_) if queued.delivery => metrics::MESSAGES_SENT.inc(),
+            Ok(_) => (),
+            Err(e) => {
+                warn!("Error sending websocket message: {:?}", e);
+                metrics::MESSAGE_SEND_ERRORS.inc();
+                let mut state = shared.state.lock();
+                state.clear();
+                state.closed = true;
+                break;
+            }
+        }
+    }
+    let _ = ws_tx.close().await;
+}
@@ -0,0 +1,245 @@
+use crate::{broker::Message, error::RequestError, settings, subscription::Delivery};
+use futures::{Stream, StreamExt};
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::debug;
+
+/// Most messages returned by a single poll.
+const MAX_BATCH: usize = 100;
+
+/// Buffers subscriptions between long polls, each identified by a cursor.
+///
+/// A cursor is `{session}.{sequence}`, where the sequence is the next message the client wants.
+/// Polling with a cursor drops the messages before it, so a lost response can be polled again.
+#[derive(Clone)]
+pub struct Polls {
+    settings: settings::Poll,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+#[derive(Default)]
+struct Sessions {
+    by_id: HashMap<String, Arc<Session>>,
+    /// Sessions opened on each channel, including those still subscribing.
+    per_channel: HashMap<String, usize>,
+    total: usize,
+}
+
+/// Counts a session against the limits, until it's dropped.
+pub struct Slot {
+    sessions: Arc<Mutex<Sessions>>,
+    channel_id: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.lock();
+        sessions.total -= 1;
+        if let Some(count) = sessions.per_channel.get_mut(&self.channel_id) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.per_channel.remove(&self.channel_id);
+            }
+        }
+    }
+}
+
+pub struct Batch {
+    pub cursor: String,
+    pub messages: Vec<Message>,
+    /// Messages were dropped from a full buffer, or lost while resubscribing.
+    pub missed: bool,
+}
+
+struct Buffer {
+    /// Sequence of the message after the last buffered.
+    next_seq: u64,
+    messages: VecDeque<Message>,
+    missed: bool,
+    last_polled: Instant,
+}
+
+impl Buffer {
+    fn first_seq(&self) -> u64 {
+        self.next_seq - self.messages.len() as u64
+    }
+}
+
+pub struct Session {
+    id: String,
+    channel_id: String,
+    buffer: Mutex<Buffer>,
+    /// Publishes `next_seq` as messages are buffered.
+    updates: watch::Sender<u64>,
+    _slot: Slot,
+}
+
+impl Polls {
+    pub fn new(settings: &settings::Poll) -> anyhow::Result<Self> {
+        if settings.cursor_ttl <= settings.timeout {
+            return Err(anyhow::anyhow!(
+                "poll.cursor_ttl must be longer than poll.timeout"
+            ));
+        }
+        Ok(Self {
+            settings: settings.clone(),
+            sessions: Default::default(),
+        })
+    }
+
+    /// Counts a new session, before subscribing for it, unless there are too many already.
+    pub fn reserve(&self, channel_id: &str) -> Result<Slot, RequestError> {
+        let mut sessions = self.sessions.lock();
+        if sessions.total >= self.settings.max_sessions {
+            return Err(RequestError::TooManyPolls { per_channel: false });
+        }
+        let count = sessions
+            .per_channel
+            .entry(channel_id.to_owned())
+            .or_default();
+        if *count >= self.settings.max_channel_sessions {
+            return Err(RequestError::TooManyPolls { per_channel: true });
+        }
+        *count += 1;
+        sessions.total += 1;
+        Ok(Slot {
+            sessions: self.sessions.clone(),
+            channel_id: channel_id.to_owned(),
+        })
+    }
+
+    /// Starts buffering a subscription, returning its session and first sequence.
+    pub fn open(
+        &self,
+        slot: Slot,
+        deliveries: impl Stream<Item = Delivery> + Send + 'static,
+    ) -> (Arc<Session>, u64) {
+        let session = Arc::new(Session {
+            id: nanoid::nanoid!(),
+            channel_id: slot.channel_id.clone(),
+            buffer: Mutex::new(Buffer {
+                next_seq: 0,
+                messages: VecDeque::new(),
+                missed: false,
+                last_polled: Instant::now(),
+            }),
+            updates: watch::channel(0).0,
+            _slot: slot,
+        });
+        self.sessions
+            .lock()
+            .by_id
+            .insert(session.id.clone(), session.clone());
+
+        let ttl = Duration::from_secs(self.settings.cursor_ttl);
+        let capacity = self.settings.buffer_size;
+        let sessions = self.sessions.clone();
+        let buffering = session.clone();
+        tokio::spawn(async move {
+            let mut deliveries = Box::pin(deliveries);
+            loop {
+                match tokio::time::timeout(ttl, deliveries.next()).await {
+                    Ok(Some(delivery)) => buffering.push(delivery, capacity),
+                    Ok(None) => break,
+                    Err(_) => (),
+                }
+                if buffering.expired(ttl) {
+                    break;
+                }
+            }
+            debug!("Poll session {:?} expired", buffering.id);
+            sessions.lock().by_id.remove(&buffering.id);
+        });
+        (session, 0)
+    }
+
+    /// Finds the session a cursor belongs to, returning it with the cursor's sequence.
+    pub fn find(
+        &self,
+        cursor: &str,
+        channel_id: &str,
+    ) -> Result<(Arc<Session>, u64), RequestError> {
+        let invalid = || RequestError::InvalidParameter {
+            name: "cursor",
+            reason: "Expected a cursor returned by a previous poll".to_owned(),
+        };
+        let (id, seq) = cursor.split_once('.').ok_or_else(invalid)?;
+        let seq = seq.parse().map_err(|_| invalid())?;
+        let session = self
+            .sessions
+            .lock()
+            .by_id
+            .get(id)
+            .cloned()
+            .ok_or(RequestError::CursorExpired)?;
+        if session.channel_id != channel_id || seq > session.buffer.lock().next_seq {
+            return Err(invalid());
+        }
+        Ok((session, seq))
+    }
+
+    /// Waits for messages from `seq` onwards, returning none if the poll timeout elapses first.
+    pub async fn poll(&self, session: &Session, seq: u64) -> Batch {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(self.settings.timeout);
+        let mut updates = session.updates.subscribe();
+        loop {
+            updates.borrow_and_update();
+            if let Some(batch) = session.take(seq, false) {
+                return batch;
+            }
+            match tokio::time::timeout_at(deadline, updates.changed()).await {
+                Ok(Ok(())) => (),
+                _ => {
+                    return session
+                        .take(seq, true)
+                        .expect("Empty batches are always taken")
+                }
+            }
+        }
+    }
+}
+
+impl Session {
+    fn push(&self, delivery: Delivery, capacity: usize) {
+        let mut buffer = self.buffer.lock();
+        match delivery {
+            Delivery::Message(message) => {
+                if buffer.messages.len() >= capacity {
+                    buffer.messages.pop_front();
+                    buffer.missed = true;
+                }
+                buffer.messages.push_back(message);
+                buffer.next_seq += 1;
+            }
+            Delivery::Resubscribed => buffer.missed = true,
+        }
+        self.updates.send_replace(buffer.next_seq);
+    }
+
+    fn expired(&self, ttl: Duration) -> bool {
+        self.buffer.lock().last_polled.elapsed() > ttl
+    }
+
+    /// Acknowledges messages before `seq`, and takes those after it unless there are none.
+    fn take(&self, seq: u64, allow_empty: bool) -> Option<Batch> {
+        let mut buffer = self.buffer.lock();
+        buffer.last_polled = Instant::now();
+        let acknowledged =
+            (seq.saturating_sub(buffer.first_seq()) as usize).min(buffer.messages.len());
+        buffer.messages.drain(..acknowledged);
+
+        if buffer.messages.is_empty() && !buffer.missed && !allow_empty {
+            return None;
+        }
+        let messages: Vec<Message> = buffer.messages.iter().take(MAX_BATCH).cloned().collect();
+        let next_seq = buffer.first_seq() + messages.len() as u64;
+        Some(Batch {
+            cursor: format!("{}.{}", self.id, next_seq),
+            messages,
+            missed: std::mem::take(&mut buffer.missed),
+        })
+    }
+}
@@ -1,47 +1,107 @@
-use crate::metrics;
+use crate::{connection::Connection, connector::Connector, metrics};
 use async_trait::async_trait;
-use redis_async::client::{paired_connect, PairedConnection};
 use redis_async::error::Error as RedisError;
 use redis_async::{resp::RespValue, resp_array};
-use std::net::SocketAddr;
 use tracing::debug;
 
 pub type Pool = deadpool::managed::Pool<Manager>;
 type RecycleResult = deadpool::managed::RecycleResult<RedisError>;
 
 pub struct Manager {
-    addr: SocketAddr,
+    connector: Connector,
 }
 
 impl Manager {
-    pub fn new(addr: SocketAddr) -> Self {
-        Self { addr }
+    pub fn new(connector: Connector) -> Self {
+        Self { connector }
     }
 }
 
 #[async_trait]
 impl deadpool::managed::Manager for Manager {
-    type Type = PairedConnection;
+    type Type = Connection;
     type Error = RedisError;
 
-    async fn create(&self) -> Result<PairedConnection, RedisError> {
+    async fn create(&self) -> Result<Connection, RedisError> {
         debug!("Creating new redis connection");
-        match paired_connect(self.addr).await {
+        let conn = match self.connector.connect().await {
             Ok(conn) => {
                 metrics::REDIS_CONNECTIONS_CREATED
                     .with_label_values(&["true"])
                     .inc();
-                Ok(conn)
+                Connection::new(conn)
             }
             Err(e) => {
                 metrics::REDIS_CONNECTION_ERRORS.inc();
-                return Err(e);
+                self.connector.reset();
+                return Err(RedisError::Internal(format!("{:#}", e)));
             }
+        };
+        if self.connector.is_sentinel() {
+            self.check_role(&conn).await?;
         }
+        Ok(conn)
     }
 
-    async fn recycle(&self, conn: &mut PairedConnection) -> RecycleResult {
+    async fn recycle(&self, conn: &mut Connection) -> RecycleResult {
+        if conn.is_broken() {
+            return Err(RedisError::Internal("Connection was lost".into()).into());
+        }
         let _: RespValue = conn.send(resp_array!["PING"]).await?;
         Ok(())
     }
 }
+
+impl Manager {
+    /// Checks a new connection reached the primary, not a replica the sentinels haven't caught up
+    /// with yet.
+    ///
+    /// Only new connections need checking, as sentinels disconnect clients of a demoted primary.
+    async fn check_role(&self, conn: &Connection) -> Result<(), RedisError> {
+        let role: Vec<RespValue> = conn.send(resp_array!["ROLE"]).await.inspect_err(|_| {
+            self.connector.reset();
+        })?;
+        if is_primary(&role) {
+            return Ok(());
+        }
+        self.connector.reset();
+        Err(RedisError::Internal(
+            "Redis server is not the primary".into(),
+        ))
+    }
+}
+
+/// Whether a `ROLE` reply is from a primary.
+fn is_primary(role: &[RespValue]) -> bool {
+    matches!(role, [RespValue::BulkString(role), ..] if role == b"master")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_role_replies() {
+        let primary = resp_array![
+            "master",
+            RespValue::Integer(3129659),
+            RespValue::Array(vec![])
+        ];
+        let replica = resp_array![
+            "slave",
+            "127.0.0.1",
+            RespValue::Integer(9999),
+            "connected",
+            RespValue::Integer(3167038)
+        ];
+        let sentinel = resp_array!["sentinel", RespValue::Array(vec!["mymaster".into()])];
+        let parts = |reply| match reply {
+            RespValue::Array(parts) => parts,
+            _ => unreachable!(),
+        };
+        assert!(is_primary(&parts(primary)));
+        assert!(!is_primary(&parts(replica)));
+        assert!(!is_primary(&parts(sentinel)));
+        assert!(!is_primary(&[]));
+    }
+}
@@ -30,6 +30,45 @@ pub fn pack(err: anyhow::Error) -> Problem {
                 return Problem::new(http::StatusCode::PAYLOAD_TOO_LARGE)
                     .detail(format!("Payload must not exceed {} bytes", limit));
             }
+            error::RequestError::InvalidParameter { name, reason } => {
+                return Problem::new(http::StatusCode::BAD_REQUEST)
+                    .title("Invalid parameter.")
+                    .detail(format!("Parameter {:?} is invalid: {}", name, reason));
+            }
+            error::RequestError::NoSubscribers => {
+                return Problem::new(http::StatusCode::CONFLICT)
+                    .title("No subscribers.")
+                    .detail("No subscriber received the message");
+            }
+            error::RequestError::CursorExpired => {
+                return Problem::new(http::StatusCode::GONE)
+                    .title("Cursor expired.")
+                    .detail(
+                        "Poll again without a cursor, messages since the last poll may be lost",
+                    );
+            }
+            error::RequestError::TooManyPolls { per_channel: true } => {
+                return Problem::new(http::StatusCode::TOO_MANY_REQUESTS)
+                    .title("Too many polls.")
+                    .detail(
+                        "The channel has too many poll cursors, poll with the cursor returned",
+                    );
+            }
+            error::RequestError::TooManyPolls { per_channel: false } => {
+                return Problem::new(http::StatusCode::SERVICE_UNAVAILABLE)
+                    .title("Too many polls.")
+                    .detail("The server has too many poll cursors, try again later");
+            }
+            error::RequestError::WebhookNotFound => {
+                return Problem::new(http::StatusCode::NOT_FOUND)
+                    .title("Webhook not found.")
+                    .detail("The webhook was never registered, or was removed");
+            }
+            error::RequestError::ShuttingDown => {
+                return Problem::new(http::StatusCode::SERVICE_UNAVAILABLE)
+                    .title("Shutting down.")
+                    .detail("The server is shutting down, connect to another one");
+            }
         }
     }
 
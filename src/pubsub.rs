@@ -0,0 +1,372 @@
+use crate::{cluster::key_slot, connection::RespConnection, connector::Connector, metrics};
+use bytes::Bytes;
+use futures::{select, FutureExt, SinkExt, StreamExt};
+use redis_async::{resp::RespValue, resp_array};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, trace, warn};
+
+pub enum Event {
+    Message {
+        key: String,
+        payload: Bytes,
+    },
+    /// A message on a key matching a subscribed pattern.
+    PatternMessage {
+        pattern: String,
+        key: String,
+        payload: Bytes,
+    },
+    /// Redis ended a subscription on its own, e.g. when a cluster slot moved to another node.
+    Unsubscribed {
+        key: String,
+    },
+    PatternUnsubscribed {
+        pattern: String,
+    },
+    /// The connection was lost, along with the subscriptions made on it.
+    Disconnected {
+        keys: Vec<String>,
+        patterns: Vec<String>,
+    },
+}
+
+/// A key, or a pattern of keys, subscribed to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Topic {
+    Key(String),
+    Pattern(String),
+}
+
+impl Topic {
+    fn name(&self) -> &str {
+        match self {
+            Topic::Key(name) | Topic::Pattern(name) => name,
+        }
+    }
+}
+
+/// An error reply from Redis to a subscribe command.
+#[derive(Error, Debug)]
+#[error("redis replied with an error: {0}")]
+pub struct ReplyError(pub String);
+
+type OnEvent = Arc<dyn Fn(Event) + Send + Sync>;
+type Ready = oneshot::Sender<Result<(), String>>;
+
+enum Command {
+    Subscribe { topic: Topic, ready: Ready },
+    Unsubscribe { topic: Topic },
+}
+
+/// A small set of Redis pub/sub connections shared by the whole process.
+///
+/// Each key or pattern is subscribed to at most once, on the connection its name hashes to. Names
+/// sharing a `{tag}` hash to the same connection, so their messages arrive in the order published.
+#[derive(Clone)]
+pub struct Pubsub {
+    connections: Vec<mpsc::UnboundedSender<Command>>,
+}
+
+impl Pubsub {
+    /// Sharded connections use SSUBSCRIBE, for Redis Cluster.
+    pub fn new(
+        connector: Connector,
+        connections: usize,
+        sharded: bool,
+        on_event: impl Fn(Event) + Send + Sync + 'static,
+    ) -> Self {
+        let on_event: OnEvent = Arc::new(on_event);
+        let connections = (0..connections.max(1))
+            .map(|_| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(run(connector.clone(), sharded, rx, on_event.clone()));
+                tx
+            })
+            .collect();
+        Self { connections }
+    }
+
+    fn connection(&self, topic: &Topic) -> &mpsc::UnboundedSender<Command> {
+        let slot = key_slot(topic.name().as_bytes()) as usize;
+        &self.connections[slot % self.connections.len()]
+    }
+
+    /// Subscribes to a key, resolving once Redis confirms the subscription.
+    ///
+    /// Subscribing to an already subscribed key resolves immediately.
+    pub async fn subscribe(&self, key: &str) -> anyhow::Result<()> {
+        self.subscribe_topic(Topic::Key(key.to_owned())).await
+    }
+
+    /// Subscribes to every key matching a glob-style pattern, with PSUBSCRIBE.
+    ///
+    /// Not for sharded connections, as Redis Cluster has no sharded pattern subscriptions.
+    pub async fn psubscribe(&self, pattern: &str) -> anyhow::Result<()> {
+        self.subscribe_topic(Topic::Pattern(pattern.to_owned()))
+            .await
+    }
+
+    async fn subscribe_topic(&self, topic: Topic) -> anyhow::Result<()> {
+        let (ready, confirmed) = oneshot::channel();
+        self.connection(&topic)
+            .send(Command::Subscribe { topic, ready })
+            .map_err(|_| anyhow::anyhow!("Pub/sub connection task has stopped"))?;
+        match confirmed.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(reply)) => Err(ReplyError(reply).into()),
+            Err(_) => Err(anyhow::anyhow!(
+                "Pub/sub connection lost before subscribing"
+            )),
+        }
+    }
+
+    pub fn unsubscribe(&self, key: &str) {
+        self.unsubscribe_topic(Topic::Key(key.to_owned()));
+    }
+
+    pub fn punsubscribe(&self, pattern: &str) {
+        self.unsubscribe_topic(Topic::Pattern(pattern.to_owned()));
+    }
+
+    fn unsubscribe_topic(&self, topic: Topic) {
+        let _ = self.connection(&topic).send(Command::Unsubscribe { topic });
+    }
+}
+
+#[derive(Default)]
+struct KeyState {
+    /// Whether a local subscriber still wants messages for the key.
+    wanted: bool,
+    /// Whether Redis has confirmed the latest subscription.
+    confirmed: bool,
+    /// SUBSCRIBE commands sent that Redis hasn't replied to yet.
+    pending: usize,
+    waiters: Vec<Ready>,
+}
+
+struct Connection {
+    sharded: bool,
+    topics: HashMap<Topic, KeyState>,
+    /// Topics of subscribe commands awaiting a reply, in the order sent. Error replies don't name
+    /// their topic, so they belong to the oldest.
+    in_flight: VecDeque<Topic>,
+    on_event: OnEvent,
+}
+
+async fn run(
+    connector: Connector,
+    sharded: bool,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    on_event: OnEvent,
+) {
+    // Connect lazily, on the first command received while disconnected.
+    while let Some(command) = commands.recv().await {
+        let connection = match connector.connect().await {
+            Ok(connection) => {
+                metrics::REDIS_CONNECTIONS_CREATED
+                    .with_label_values(&["false"])
+                    .inc();
+                connection
+            }
+            Err(e) => {
+                metrics::REDIS_CONNECTION_ERRORS.inc();
+                connector.reset();
+                error!("Failed connecting to redis for pub/sub: {:?}", e);
+                // Dropping the command fails any subscriber waiting on it.
+                continue;
+            }
+        };
+
+        let mut state = Connection {
+            sharded,
+            topics: HashMap::new(),
+            in_flight: VecDeque::new(),
+            on_event: on_event.clone(),
+        };
+        if let Err(e) = state.serve(connection, command, &mut commands).await {
+            error!("Redis pub/sub connection error: {:?}", e);
+            connector.reset();
+        }
+        let (mut keys, mut patterns) = (vec![], vec![]);
+        for (topic, _) in state.topics.into_iter().filter(|(_, state)| state.wanted) {
+            match topic {
+                Topic::Key(key) => keys.push(key),
+                Topic::Pattern(pattern) => patterns.push(pattern),
+            }
+        }
+        (state.on_event)(Event::Disconnected { keys, patterns });
+    }
+    debug!("Pub/sub connection task stopping");
+}
+
+impl Connection {
+    async fn serve(
+        &mut self,
+        connection: RespConnection,
+        first_command: Command,
+        commands: &mut mpsc::UnboundedReceiver<Command>,
+    ) -> anyhow::Result<()> {
+        let (mut sink, stream) = connection.split();
+        let mut stream = stream.fuse();
+
+        for request in self.handle_command(first_command) {
+            sink.send(request).await?;
+        }
+
+        loop {
+            select! {
+                command = commands.recv().fuse() => match command {
+                    Some(command) => {
+                        for request in self.handle_command(command) {
+                            sink.send(request).await?;
+                        }
+                    }
+                    None => return Ok(()),
+                },
+                frame = stream.next() => match frame {
+                    Some(frame) => self.handle_frame(frame?)?,
+                    None => return Err(anyhow::anyhow!("Connection closed by redis")),
+                },
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) -> Vec<RespValue> {
+        let commands = |topic: &Topic| match (topic, self.sharded) {
+            (Topic::Pattern(_), _) => ("PSUBSCRIBE", "PUNSUBSCRIBE"),
+            (Topic::Key(_), true) => ("SSUBSCRIBE", "SUNSUBSCRIBE"),
+            (Topic::Key(_), false) => ("SUBSCRIBE", "UNSUBSCRIBE"),
+        };
+        match command {
+            Command::Subscribe { topic, ready } => {
+                let (subscribe, _) = commands(&topic);
+                let state = self.topics.entry(topic.clone()).or_default();
+                if state.confirmed {
+                    let _ = ready.send(Ok(()));
+                    return vec![];
+                }
+                state.waiters.push(ready);
+                if state.wanted {
+                    return vec![];
+                }
+                trace!("Subscribing to {:?}", topic);
+                state.wanted = true;
+                state.pending += 1;
+                let request = resp_array![subscribe, topic.name()];
+                self.in_flight.push_back(topic);
+                vec![request]
+            }
+            Command::Unsubscribe { topic } => {
+                let (_, unsubscribe) = commands(&topic);
+                let state = match self.topics.get_mut(&topic) {
+                    Some(state) if state.wanted => state,
+                    _ => return vec![],
+                };
+                trace!("Unsubscribing from {:?}", topic);
+                state.wanted = false;
+                state.confirmed = false;
+                state.waiters.clear();
+                vec![resp_array![unsubscribe, topic.name()]]
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, frame: RespValue) -> anyhow::Result<()> {
+        let mut parts = match frame {
+            RespValue::Array(parts) => parts.into_iter(),
+            RespValue::Error(e) => return self.handle_error(e),
+            other => {
+                warn!("Unexpected pub/sub frame: {:?}", other);
+                return Ok(());
+            }
+        };
+        let (kind, key) = match (parts.next(), parts.next()) {
+            (Some(RespValue::BulkString(kind)), Some(RespValue::BulkString(key))) => {
+                (kind, String::from_utf8_lossy(&key).into_owned())
+            }
+            _ => return Ok(()),
+        };
+        let topic = match kind.as_slice() {
+            b"psubscribe" | b"punsubscribe" | b"pmessage" => Topic::Pattern(key),
+            _ => Topic::Key(key),
+        };
+
+        match kind.as_slice() {
+            b"message" | b"smessage" => match parts.next() {
+                Some(RespValue::BulkString(payload)) => (self.on_event)(Event::Message {
+                    key: topic.name().to_owned(),
+                    payload: payload.into(),
+                }),
+                _ => {
+                    metrics::REDIS_SUBSCRIBE_UNEXPECTED_MESSAGE_TYPES.inc();
+                    error!("Received unexpected redis type, ignoring");
+                }
+            },
+            b"pmessage" => match (parts.next(), parts.next()) {
+                (Some(RespValue::BulkString(key)), Some(RespValue::BulkString(payload))) => {
+                    (self.on_event)(Event::PatternMessage {
+                        pattern: topic.name().to_owned(),
+                        key: String::from_utf8_lossy(&key).into_owned(),
+                        payload: payload.into(),
+                    })
+                }
+                _ => {
+                    metrics::REDIS_SUBSCRIBE_UNEXPECTED_MESSAGE_TYPES.inc();
+                    error!("Received unexpected redis type, ignoring");
+                }
+            },
+            b"subscribe" | b"ssubscribe" | b"psubscribe" => {
+                if let Some(i) = self.in_flight.iter().position(|t| t == &topic) {
+                    self.in_flight.remove(i);
+                }
+                if let Some(state) = self.topics.get_mut(&topic) {
+                    state.pending = state.pending.saturating_sub(1);
+                    if state.pending == 0 && state.wanted {
+                        state.confirmed = true;
+                        for waiter in state.waiters.drain(..) {
+                            let _ = waiter.send(Ok(()));
+                        }
+                    }
+                }
+            }
+            b"unsubscribe" | b"sunsubscribe" | b"punsubscribe" => match self.topics.get(&topic) {
+                Some(state) if state.pending > 0 => (),
+                Some(state) if state.wanted => {
+                    debug!("Redis ended the subscription to {:?}", topic);
+                    self.topics.remove(&topic);
+                    (self.on_event)(match topic {
+                        Topic::Key(key) => Event::Unsubscribed { key },
+                        Topic::Pattern(pattern) => Event::PatternUnsubscribed { pattern },
+                    });
+                }
+                Some(_) => {
+                    self.topics.remove(&topic);
+                }
+                None => (),
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn handle_error(&mut self, reply: String) -> anyhow::Result<()> {
+        let topic = match self.in_flight.pop_front() {
+            Some(topic) => topic,
+            None => return Err(anyhow::anyhow!("Redis error: {}", reply)),
+        };
+        debug!("Subscribing to {:?} failed: {}", topic, reply);
+        if let Some(state) = self.topics.get_mut(&topic) {
+            state.pending = state.pending.saturating_sub(1);
+            if state.pending == 0 {
+                for waiter in state.waiters.drain(..) {
+                    let _ = waiter.send(Err(reply.clone()));
+                }
+                self.topics.remove(&topic);
+            }
+        }
+        Ok(())
+    }
+}
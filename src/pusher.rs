@@ -0,0 +1,246 @@
+use crate::{auth::AuthError, broker, settings};
+use chrono::Utc;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::debug;
+use warp::ws::Message;
+
+/// Seconds a signed API request is accepted for, either side of the server's clock.
+const MAX_REQUEST_AGE: i64 = 600;
+/// Most channels an event may be triggered on at once.
+pub const MAX_EVENT_CHANNELS: usize = 100;
+/// Longest channel name Pusher allows.
+const MAX_CHANNEL_NAME_LENGTH: usize = 164;
+/// Message header naming the connection that triggered an event, which doesn't receive it.
+pub const SOCKET_ID_HEADER: &str = "pusher-socket-id";
+/// Event name for messages published without one, through the webchannel API.
+const DEFAULT_EVENT: &str = "message";
+/// Pusher's close code for connections to an unknown app key, which aren't retried.
+pub const CLOSE_APP_NOT_FOUND: u16 = 4001;
+/// Starts the webchannel channels Pusher channels are relayed through, so the app key alone
+/// can't subscribe to channels authorized by webchannel tokens.
+const CHANNEL_PREFIX: &str = "pusher:";
+
+const DIGITS: [char; 10] = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
+
+/// An event triggered through `POST /apps/{app_id}/events`.
+#[derive(Debug, Deserialize)]
+pub struct TriggerRequest {
+    pub name: String,
+    pub data: String,
+    pub channel: Option<String>,
+    pub channels: Option<Vec<String>>,
+    /// Excludes this connection from receiving the event.
+    pub socket_id: Option<String>,
+}
+
+/// Sent by Pusher clients, like `pusher:subscribe`.
+#[derive(Debug, Deserialize)]
+pub struct ClientEvent {
+    pub event: String,
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Subscribe {
+    pub channel: String,
+    /// `{key}:{signature}`, from the app's backend, for private channels.
+    pub auth: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Unsubscribe {
+    pub channel: String,
+}
+
+/// Why a subscription was refused.
+#[derive(Debug)]
+pub struct SubscriptionError {
+    pub status: u16,
+    pub error: String,
+}
+
+impl SubscriptionError {
+    pub fn unauthorized() -> Self {
+        Self {
+            status: 401,
+            error: "The connection is unauthorized".to_owned(),
+        }
+    }
+
+    pub fn invalid(error: impl Into<String>) -> Self {
+        Self {
+            status: 400,
+            error: error.into(),
+        }
+    }
+}
+
+/// Sent to Pusher clients, which expect most `data` to be JSON encoded as a string.
+#[derive(Debug, Serialize)]
+pub struct ServerEvent {
+    pub event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    pub data: serde_json::Value,
+}
+
+impl ServerEvent {
+    pub fn connection_established(socket_id: &str, activity_timeout: u64) -> Self {
+        let data = json!({ "socket_id": socket_id, "activity_timeout": activity_timeout });
+        Self {
+            event: "pusher:connection_established".to_owned(),
+            channel: None,
+            data: data.to_string().into(),
+        }
+    }
+
+    pub fn subscription_succeeded(channel: &str) -> Self {
+        Self {
+            event: "pusher_internal:subscription_succeeded".to_owned(),
+            channel: Some(channel.to_owned()),
+            data: "{}".into(),
+        }
+    }
+
+    /// Tells the client a subscription was refused, raised on the channel by pusher-js.
+    pub fn subscription_error(channel: &str, error: SubscriptionError) -> Self {
+        let kind = match error.status {
+            401 => "AuthError",
+            _ => "SubscriptionError",
+        };
+        Self {
+            event: "pusher:subscription_error".to_owned(),
+            channel: Some(channel.to_owned()),
+            data: json!({ "type": kind, "error": error.error, "status": error.status }),
+        }
+    }
+
+    pub fn error(code: Option<u16>, message: impl Into<String>) -> Self {
+        Self {
+            event: "pusher:error".to_owned(),
+            channel: None,
+            data: json!({ "code": code, "message": message.into() }),
+        }
+    }
+
+    pub fn pong() -> Self {
+        Self {
+            event: "pusher:pong".to_owned(),
+            channel: None,
+            data: json!({}),
+        }
+    }
+
+    /// Relays a channel message, unless its payload isn't text.
+    pub fn message(channel_id: &str, message: &broker::Message) -> Option<Self> {
+        let data = std::str::from_utf8(&message.payload).ok()?;
+        Some(Self {
+            event: message.event.as_deref().unwrap_or(DEFAULT_EVENT).to_owned(),
+            channel: Some(channel_id.to_owned()),
+            data: data.into(),
+        })
+    }
+
+    pub fn to_message(&self) -> Message {
+        Message::text(serde_json::to_string(self).expect("Failed to serialize event"))
+    }
+}
+
+/// A connection's ID, two numbers as Pusher formats them.
+pub fn socket_id() -> String {
+    format!(
+        "{}.{}",
+        nanoid::nanoid!(9, &DIGITS),
+        nanoid::nanoid!(9, &DIGITS)
+    )
+}
+
+/// The webchannel channel a Pusher channel's events are published on.
+pub fn channel_id(channel: &str) -> String {
+    format!("{}{}", CHANNEL_PREFIX, channel)
+}
+
+/// Whether a channel name is one Pusher would accept.
+pub fn valid_channel_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_CHANNEL_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_=@,.;".contains(c))
+}
+
+/// Checks a hex HMAC-SHA256 signature of `data`, keyed with the app secret.
+fn verify_signature(settings: &settings::Pusher, data: &str, signature: &str) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, settings.secret.as_bytes());
+    match hex::decode(signature) {
+        Ok(signature) => hmac::verify(&key, data.as_bytes(), &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Checks an HTTP API request, signed as Pusher's server libraries do.
+pub fn verify_request(
+    settings: &settings::Pusher,
+    method: &str,
+    path: &str,
+    query: &str,
+    body: &[u8],
+) -> Result<(), AuthError> {
+    let mut params: Vec<(String, String)> = url::form_urlencoded::parse(query.as_bytes())
+        .map(|(name, value)| (name.to_lowercase(), value.into_owned()))
+        .collect();
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.clone())
+    };
+    if param("auth_key").as_deref() != Some(settings.key.as_str()) {
+        debug!("Pusher request has an unknown key");
+        return Err(AuthError::InvalidCredentials);
+    }
+    let timestamp: Option<i64> = param("auth_timestamp").and_then(|t| t.parse().ok());
+    match timestamp {
+        Some(timestamp) if (Utc::now().timestamp() - timestamp).abs() <= MAX_REQUEST_AGE => (),
+        _ => {
+            debug!("Pusher request timestamp is missing or expired");
+            return Err(AuthError::InvalidCredentials);
+        }
+    }
+    if !body.is_empty() && param("body_md5") != Some(format!("{:x}", md5::compute(body))) {
+        debug!("Pusher request body doesn't match its MD5");
+        return Err(AuthError::InvalidCredentials);
+    }
+    let signature = param("auth_signature").unwrap_or_default();
+
+    params.retain(|(name, _)| name != "auth_signature");
+    params.sort();
+    let params = params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&");
+    let signed = format!("{}\n{}\n{}", method, path, params);
+    if !verify_signature(settings, &signed, &signature) {
+        debug!("Pusher request signature is invalid");
+        return Err(AuthError::InvalidCredentials);
+    }
+    Ok(())
+}
+
+/// Checks the `auth` for a private channel subscription, signed by the app's backend.
+pub fn verify_channel_auth(
+    settings: &settings::Pusher,
+    socket_id: &str,
+    channel: &str,
+    auth: Option<&str>,
+) -> bool {
+    let signature = match auth.and_then(|auth| auth.split_once(':')) {
+        Some((key, signature)) if key == settings.key => signature,
+        _ => return false,
+    };
+    verify_signature(settings, &format!("{}:{}", socket_id, channel), signature)
+}
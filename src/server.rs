@@ -0,0 +1,194 @@
+use crate::{deflate, settings, tls};
+use futures::StreamExt;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, info, warn};
+use warp::http::{header, Request, Response};
+use warp::hyper::{
+    self,
+    server::accept,
+    service::{make_service_fn, service_fn, Service},
+    Body,
+};
+
+/// How long to wait after failing to accept a connection, e.g. when out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// How long a client has to complete a TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most TLS handshakes in progress at once, beyond which accepting waits.
+const MAX_HANDSHAKES: usize = 256;
+
+/// An accepted connection, optionally with TLS.
+pub enum Transport {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Transport {
+    fn remote_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Transport::Plain(stream) => stream.peer_addr(),
+            Transport::Tls(stream) => stream.get_ref().0.peer_addr(),
+        }
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Serves requests on the listen address, over connections that can compress WebSocket
+/// messages, with TLS when configured. HTTP/2 is negotiated through ALPN, or with prior
+/// knowledge over plain connections.
+///
+/// Takes `warp::service` of the routes, which find each connection's `deflate::Handle` in
+/// the request extensions. Requests are logged here as `warp::log("webchannel")` would, which
+/// can't see the remote address through a service.
+///
+/// Once `shutdown` completes, stops accepting connections and returns when the requests in
+/// flight are answered.
+pub async fn run<S, F>(service: S, settings: &settings::Server, shutdown: F) -> anyhow::Result<()>
+where
+    F: Future<Output = ()>,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    let acceptor = settings.tls.as_ref().map(tls::acceptor).transpose()?;
+    let listener = TcpListener::bind(settings.listen_address).await?;
+    let accepted = futures::stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let _ = stream.set_nodelay(true);
+                    return Some((stream, listener));
+                }
+                Err(e) => {
+                    warn!("Failed accepting a connection: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+            }
+        }
+    });
+    // Handshakes run alongside each other, so a slow client doesn't hold up accepting.
+    let incoming = accepted
+        .map(move |stream| {
+            let acceptor = acceptor.clone();
+            async move {
+                let acceptor = match acceptor {
+                    Some(acceptor) => acceptor,
+                    None => return Some(Transport::Plain(stream)),
+                };
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => Some(Transport::Tls(Box::new(stream))),
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake failed: {}", e);
+                        None
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake timed out");
+                        None
+                    }
+                }
+            }
+        })
+        .buffer_unordered(MAX_HANDSHAKES)
+        .filter_map(|transport| async move {
+            transport.map(|transport| Ok::<_, Infallible>(deflate::Stream::new(transport)))
+        });
+
+    let make_service = make_service_fn(move |stream: &deflate::Stream<Transport>| {
+        let service = service.clone();
+        let remote_addr = stream.get_ref().remote_addr().ok();
+        let handle = stream.handle();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
+                request.extensions_mut().insert(handle.clone());
+                let mut service = service.clone();
+                async move {
+                    let started = Instant::now();
+                    let method = request.method().clone();
+                    let path = request.uri().path().to_owned();
+                    let version = request.version();
+                    let referer = header_or_dash(&request, header::REFERER);
+                    let user_agent = header_or_dash(&request, header::USER_AGENT);
+                    let response = service.call(request).await?;
+                    info!(
+                        target: "webchannel",
+                        "{} \"{} {} {:?}\" {} \"{}\" \"{}\" {:?}",
+                        remote_addr.map_or("-".to_owned(), |addr| addr.to_string()),
+                        method,
+                        path,
+                        version,
+                        response.status().as_u16(),
+                        referer,
+                        user_agent,
+                        started.elapsed(),
+                    );
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    hyper::Server::builder(accept::from_stream(incoming))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    Ok(())
+}
+
+fn header_or_dash(request: &Request<Body>, name: header::HeaderName) -> String {
+    request
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+        .to_owned()
+}
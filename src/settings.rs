@@ -2,13 +2,129 @@ use anyhow::Result;
 use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tracing::info;
 
+/// Credentials and TLS from `redis.url` apply to the discovered primary, sentinels have their own.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Sentinel {
+    pub addresses: Vec<SocketAddr>,
+    pub master_name: String,
+    /// For sentinels with `requirepass` or ACLs.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Connect to sentinels with TLS, using the `redis.tls` options.
+    #[serde(default)]
+    pub tls: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Cluster {
+    /// Seed nodes to discover the slot map from.
+    pub nodes: Vec<SocketAddr>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Tls {
+    /// PEM bundle of CA certificates to trust, instead of the system roots.
+    pub ca_file: Option<PathBuf>,
+    /// Name to verify server certificates against, instead of the URL host.
+    pub server_name: Option<String>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Redis {
-    pub address: SocketAddr,
+    /// `host:port`, re-resolved on each connection, or `unix:///path/to/redis.sock`.
+    pub address: String,
+    /// A `redis://` or `rediss://` URL with optional credentials and database, overriding
+    /// `address`.
+    pub url: Option<String>,
+    /// TLS options for `rediss://` URLs.
+    pub tls: Option<Tls>,
     pub pool_size: usize,
+    /// Pub/sub connections shared by every subscriber on this node.
+    pub pubsub_connections: usize,
+    pub channel_capacity: usize,
+    /// Discover the primary through Sentinel, instead of connecting to `address`.
+    pub sentinel: Option<Sentinel>,
+    /// Connect to a Redis Cluster through these nodes, instead of connecting to `address`.
+    pub cluster: Option<Cluster>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Memory {
+    pub channel_capacity: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Nats {
+    /// Server URLs, which may include credentials.
+    pub servers: Vec<String>,
+    pub credentials_file: Option<PathBuf>,
+    /// Channels are published on `{subject_prefix}.{channel_id}`.
+    pub subject_prefix: String,
+    pub channel_capacity: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Postgres {
+    /// A `postgres://` URL or `key=value` connection string. Connections don't use TLS, so
+    /// `sslmode=require` is refused.
+    pub url: String,
+    /// Channels are notified on `{channel_prefix}{channel_id}`.
+    pub channel_prefix: String,
+    pub channel_capacity: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Poll {
+    /// Seconds a long poll waits for messages.
+    pub timeout: u64,
+    /// Seconds messages are buffered for a cursor between polls.
+    pub cursor_ttl: u64,
+    /// Messages buffered per cursor, the oldest are dropped beyond this.
+    pub buffer_size: usize,
+    /// Most cursors buffering at once, new polls without a cursor are refused beyond this.
+    pub max_sessions: usize,
+    /// Most cursors buffering a single channel at once.
+    pub max_channel_sessions: usize,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BrokerBackend {
+    Redis,
+    Memory,
+    Nats,
+    Postgres,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Broker {
+    pub backend: BrokerBackend,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct History {
+    pub enabled: bool,
+    /// Channel ID prefixes to keep history for, or every channel when unset.
+    pub channel_prefixes: Option<Vec<String>>,
+    /// Messages kept per channel.
+    pub max_length: usize,
+    /// Seconds to keep a channel's history after its last message.
+    pub ttl: u64,
+}
+
+impl History {
+    pub fn enabled_for(&self, channel_id: &str) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        match &self.channel_prefixes {
+            Some(prefixes) => prefixes.iter().any(|p| channel_id.starts_with(p.as_str())),
+            None => true,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -17,11 +133,103 @@ pub struct Server {
     pub cors_origins: Option<Vec<String>>,
     pub cors_allow_any_origin: bool,
     pub path_prefix: Option<String>,
+    /// Terminate TLS, instead of serving plain HTTP.
+    pub tls: Option<ServerTls>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ServerTls {
+    /// PEM certificate chain, reloaded when changed or on SIGHUP.
+    pub cert_file: PathBuf,
+    /// PEM private key, PKCS#8 or RSA.
+    pub key_file: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Upstream {
+    /// Publish frames sent by WebSocket subscribers to `{channel_id}:up`, instead of
+    /// disconnecting them.
+    pub enabled: bool,
+    pub max_message_size: usize,
+}
+
+/// What to do with a message for a client whose send queue is full.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePolicy {
+    /// Drop the oldest queued message to make room.
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+    /// Close the connection, so the client can reconnect and resume from history.
+    Disconnect,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebSocket {
+    /// Seconds between pings, which keep proxies from closing idle connections, or 0 for none.
+    pub ping_interval: u64,
+    /// Seconds to wait for a pong before closing the connection.
+    pub pong_timeout: u64,
+    /// Seconds without messages either way before closing the connection, or 0 for no limit.
+    pub idle_timeout: u64,
+    /// Messages queued for a client that isn't keeping up, before `send_queue_policy` applies.
+    pub send_queue_size: usize,
+    pub send_queue_policy: QueuePolicy,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Deflate {
+    /// Compress WebSocket messages for clients offering permessage-deflate.
+    pub enabled: bool,
+    /// Base 2 log of the compression window, from 9 to 15, smaller windows use less memory.
+    pub window_bits: u8,
+    /// Bytes a message must have to be compressed.
+    pub min_size: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Webhooks {
+    /// Seconds to wait for a webhook's response.
+    pub timeout: u64,
+    /// Deliveries made before giving up on a message.
+    pub max_attempts: u32,
+    /// Seconds before retrying a failed delivery, doubled on each retry.
+    pub retry_delay: u64,
+    /// Messages waiting for delivery per webhook, new messages are dropped beyond this.
+    pub queue_size: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Pusher {
+    /// Serve Pusher's HTTP API and WebSocket protocol, for pusher-js and Laravel Echo.
+    pub enabled: bool,
+    pub app_id: String,
+    /// Given to clients, and signing private channel subscriptions with `secret`.
+    pub key: String,
+    /// Signs API requests and private channel subscriptions.
+    pub secret: String,
+    /// Seconds without messages before clients ping, sent when they connect.
+    pub activity_timeout: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Shutdown {
+    /// Seconds to wait for connections to close after SIGTERM or SIGINT, before exiting.
+    pub drain_timeout: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Multiplex {
+    /// Most channels a single multiplexed connection may subscribe to.
+    pub max_channels: usize,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Metrics {
     pub auth_enabled: bool,
+    /// Count subscribers by channel as well, making a series for each subscribed channel.
+    pub channel_subscribers: bool,
     pub auth_username: Option<String>,
     pub auth_password: Option<String>,
 }
@@ -48,8 +256,21 @@ pub struct Channel {
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Settings {
+    pub broker: Broker,
     pub redis: Redis,
+    pub memory: Memory,
+    pub nats: Nats,
+    pub postgres: Postgres,
+    pub history: History,
+    pub poll: Poll,
+    pub upstream: Upstream,
+    pub multiplex: Multiplex,
+    pub websocket: WebSocket,
+    pub deflate: Deflate,
+    pub webhooks: Webhooks,
+    pub pusher: Pusher,
     pub server: Server,
+    pub shutdown: Shutdown,
     pub channel: Channel,
     pub metrics: Metrics,
 }
@@ -59,13 +280,53 @@ impl Settings {
         let mut s = Config::new();
 
         // Set defaults.
+        s.set_default("broker.backend", "redis")?;
         s.set_default("redis.address", "127.0.0.1:6379")?;
         s.set_default("redis.pool_size", 1024)?;
+        s.set_default("redis.pubsub_connections", 1)?;
+        s.set_default("redis.channel_capacity", 128)?;
+        s.set_default("memory.channel_capacity", 128)?;
+        s.set_default("nats.servers", vec!["nats://127.0.0.1:4222"])?;
+        s.set_default("nats.subject_prefix", "wc.channel")?;
+        s.set_default("nats.channel_capacity", 128)?;
+        s.set_default("postgres.url", "postgres://postgres@127.0.0.1/postgres")?;
+        s.set_default("postgres.channel_prefix", "wc:")?;
+        s.set_default("postgres.channel_capacity", 128)?;
+        s.set_default("history.enabled", false)?;
+        s.set_default("history.max_length", 100)?;
+        s.set_default("history.ttl", 3600)?;
+        s.set_default("poll.timeout", 25)?;
+        s.set_default("poll.cursor_ttl", 60)?;
+        s.set_default("poll.buffer_size", 256)?;
+        s.set_default("poll.max_sessions", 10000)?;
+        s.set_default("poll.max_channel_sessions", 100)?;
+        s.set_default("upstream.enabled", false)?;
+        s.set_default("upstream.max_message_size", 4096)?;
+        s.set_default("multiplex.max_channels", 64)?;
+        s.set_default("websocket.ping_interval", 30)?;
+        s.set_default("websocket.pong_timeout", 10)?;
+        s.set_default("websocket.idle_timeout", 0)?;
+        s.set_default("websocket.send_queue_size", 1024)?;
+        s.set_default("websocket.send_queue_policy", "disconnect")?;
+        s.set_default("deflate.enabled", false)?;
+        s.set_default("deflate.window_bits", 15)?;
+        s.set_default("deflate.min_size", 256)?;
+        s.set_default("webhooks.timeout", 10)?;
+        s.set_default("webhooks.max_attempts", 5)?;
+        s.set_default("webhooks.retry_delay", 1)?;
+        s.set_default("webhooks.queue_size", 1024)?;
         s.set_default("server.listen_address", "0.0.0.0:8080")?;
         s.set_default("server.cors_allow_any_origin", false)?;
+        s.set_default("shutdown.drain_timeout", 30)?;
+        s.set_default("pusher.enabled", false)?;
+        s.set_default("pusher.app_id", "")?;
+        s.set_default("pusher.key", "")?;
+        s.set_default("pusher.secret", "")?;
+        s.set_default("pusher.activity_timeout", 120)?;
         s.set_default("channel.ttl", 3600)?;
         s.set_default("channel.secret_key", "WAEgmUZx6H".to_string())?;
         s.set_default("metrics.auth_enabled", false)?;
+        s.set_default("metrics.channel_subscribers", false)?;
 
         if let Some(config_file) = config_file {
             info!("Reading config file: {:?}", config_file);
@@ -74,6 +335,64 @@ impl Settings {
 
         s.merge(Environment::with_prefix("WC").separator("__"))?;
 
-        s.try_into()
+        let settings: Settings = s.try_into()?;
+        if !(9..=15).contains(&settings.deflate.window_bits) {
+            return Err(ConfigError::Message(
+                "deflate.window_bits must be from 9 to 15".to_owned(),
+            ));
+        }
+        if let backend @ (BrokerBackend::Nats | BrokerBackend::Postgres) = settings.broker.backend {
+            if settings.history.enabled {
+                return Err(ConfigError::Message(format!(
+                    "history can't be enabled with the {:?} backend",
+                    backend
+                )));
+            }
+        }
+        let pusher = &settings.pusher;
+        if pusher.enabled
+            && (pusher.app_id.is_empty() || pusher.key.is_empty() || pusher.secret.is_empty())
+        {
+            return Err(ConfigError::Message(
+                "pusher.app_id, pusher.key and pusher.secret must be set".to_owned(),
+            ));
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_toml(name: &str, toml: &str) -> Result<Settings, ConfigError> {
+        let path = std::env::temp_dir().join(format!("webchannel-{}.toml", name));
+        std::fs::write(&path, toml).unwrap();
+        let settings = Settings::new(Some(&path));
+        std::fs::remove_file(&path).unwrap();
+        settings
+    }
+
+    #[test]
+    fn rejects_history_with_nats() {
+        let error = from_toml(
+            "nats-history",
+            "[broker]\nbackend = \"nats\"\n[history]\nenabled = true\n",
+        )
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("history"), "{}", error);
+        assert!(from_toml("nats", "[broker]\nbackend = \"nats\"\n").is_ok());
+    }
+
+    #[test]
+    fn rejects_history_with_postgres() {
+        let error = from_toml(
+            "postgres-history",
+            "[broker]\nbackend = \"postgres\"\n[history]\nenabled = true\n",
+        )
+        .err()
+        .unwrap();
+        assert!(error.to_string().contains("history"), "{}", error);
     }
 }
@@ -0,0 +1,111 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify};
+use warp::ws::Message;
+
+/// WebSocket close code for connections closed as the server goes away.
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// Tells WebSocket connections when the server is shutting down, and tracks them until
+/// they're closed.
+#[derive(Clone)]
+pub struct Shutdown {
+    trigger: Arc<watch::Sender<bool>>,
+    draining: watch::Receiver<bool>,
+    connections: Arc<Connections>,
+}
+
+struct Connections {
+    open: Mutex<usize>,
+    closed: Notify,
+}
+
+/// Held by a WebSocket connection until it's closed.
+pub struct Connection {
+    connections: Arc<Connections>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock();
+        *open -= 1;
+        if *open == 0 {
+            self.connections.closed.notify_waiters();
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (trigger, draining) = watch::channel(false);
+        Self {
+            trigger: Arc::new(trigger),
+            draining,
+            connections: Arc::new(Connections {
+                open: Mutex::new(0),
+                closed: Notify::new(),
+            }),
+        }
+    }
+
+    /// Starts draining: readiness fails, upgrades are refused, and connections are closed.
+    pub fn start(&self) {
+        let _ = self.trigger.send(true);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Waits until the server starts shutting down.
+    pub async fn draining(&self) {
+        let mut draining = self.draining.clone();
+        while !*draining.borrow() {
+            if draining.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub fn connection(&self) -> Connection {
+        *self.connections.open.lock() += 1;
+        Connection {
+            connections: self.connections.clone(),
+        }
+    }
+
+    /// Waits until every WebSocket connection is closed.
+    pub async fn drained(&self) {
+        loop {
+            let closed = self.connections.closed.notified();
+            if *self.connections.open.lock() == 0 {
+                return;
+            }
+            closed.await;
+        }
+    }
+
+    pub fn open_connections(&self) -> usize {
+        *self.connections.open.lock()
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The close frame sent to WebSocket clients as the server shuts down.
+pub fn close_message() -> Message {
+    Message::close_with(CLOSE_GOING_AWAY, "Server shutting down")
+}
+
+/// Waits for SIGTERM or SIGINT.
+pub async fn signalled() -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    futures::future::select(Box::pin(terminate.recv()), Box::pin(interrupt.recv())).await;
+    Ok(())
+}
@@ -0,0 +1,175 @@
+use crate::{
+    broker::{self, ChannelPattern, HistoryStart, MessageId, MessageStream, PatternStream},
+    environment::Environment,
+    metrics,
+};
+use futures::StreamExt;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+const RESUBSCRIBE_MIN_DELAY: Duration = Duration::from_millis(100);
+const RESUBSCRIBE_MAX_DELAY: Duration = Duration::from_secs(10);
+
+pub enum Delivery<M = broker::Message> {
+    Message(M),
+    /// The subscription ended and was made again, messages in between may be missing.
+    Resubscribed,
+}
+
+struct Recovery {
+    env: Environment,
+    channel_id: String,
+    /// The last message seen with a history ID, to replay what was missed from.
+    last_id: Option<MessageId>,
+    messages: Option<MessageStream>,
+}
+
+/// Relays a subscription's messages, subscribing again with backoff whenever it ends.
+///
+/// Ends only when the consumer drops it, as with a closed WebSocket.
+pub fn recover(
+    env: Environment,
+    channel_id: &str,
+    messages: MessageStream,
+) -> impl futures::Stream<Item = Delivery> {
+    let state = Recovery {
+        env,
+        channel_id: channel_id.to_owned(),
+        last_id: None,
+        messages: Some(messages),
+    };
+    futures::stream::unfold(state, |mut state| async move {
+        if let Some(messages) = &mut state.messages {
+            match messages.next().await {
+                Some(Ok(message)) => {
+                    state.last_id = message.id.or(state.last_id);
+                    return Some((Delivery::Message(message), state));
+                }
+                Some(Err(e)) => warn!("Channel subscription error: {:?}", e),
+                None => debug!("Channel subscription ended"),
+            }
+            state.messages = None;
+        }
+
+        let mut delay = RESUBSCRIBE_MIN_DELAY;
+        loop {
+            tokio::time::sleep(delay).await;
+            let start = state.last_id.map(HistoryStart::After);
+            match state.env.broker.resume(&state.channel_id, start).await {
+                Ok(messages) => {
+                    info!("Resubscribed to channel {:?}", state.channel_id);
+                    metrics::RESUBSCRIPTIONS.inc();
+                    state.messages = Some(messages);
+                    return Some((Delivery::Resubscribed, state));
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed resubscribing to channel {:?}, retrying in {:?}: {:#}",
+                        state.channel_id, delay, e
+                    );
+                    delay = (delay * 2).min(RESUBSCRIBE_MAX_DELAY);
+                }
+            }
+        }
+    })
+}
+
+/// Relays a pattern subscription's messages, subscribing again with backoff whenever it ends.
+///
+/// Messages published while resubscribing are missed, as patterns have no history.
+pub fn recover_pattern(
+    env: Environment,
+    pattern: ChannelPattern,
+    messages: PatternStream,
+) -> impl futures::Stream<Item = Delivery<(String, broker::Message)>> {
+    futures::stream::unfold(
+        (env, pattern, Some(messages)),
+        |(env, pattern, mut messages)| async move {
+            if let Some(stream) = &mut messages {
+                match stream.next().await {
+                    Some(Ok(message)) => {
+                        return Some((Delivery::Message(message), (env, pattern, messages)))
+                    }
+                    Some(Err(e)) => warn!("Channel pattern subscription error: {:?}", e),
+                    None => debug!("Channel pattern subscription ended"),
+                }
+            }
+
+            let mut delay = RESUBSCRIBE_MIN_DELAY;
+            loop {
+                tokio::time::sleep(delay).await;
+                match env.broker.psubscribe(&pattern).await {
+                    Ok(stream) => {
+                        info!("Resubscribed to channel pattern {:?}", pattern.to_string());
+                        metrics::RESUBSCRIPTIONS.inc();
+                        return Some((Delivery::Resubscribed, (env, pattern, Some(stream))));
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed resubscribing to channel pattern {:?}, retrying in {:?}: {:#}",
+                            pattern.to_string(),
+                            delay,
+                            e
+                        );
+                        delay = (delay * 2).min(RESUBSCRIBE_MAX_DELAY);
+                    }
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{BrokerBackend, Settings};
+    use futures::stream;
+
+    async fn environment() -> Environment {
+        let mut settings = Settings::new(None).unwrap();
+        settings.broker.backend = BrokerBackend::Memory;
+        Environment::new(settings).await.unwrap()
+    }
+
+    async fn publish(env: &Environment, channel_id: &str, payload: &'static str) {
+        let message = broker::Message::new(payload.into());
+        env.broker.publish(channel_id, message).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn recovers_lost_subscriptions() {
+        let env = environment().await;
+        // Starts from a subscription that has ended, as when the broker connection drops.
+        let deliveries = recover(env.clone(), "foo", stream::empty().boxed());
+        futures::pin_mut!(deliveries);
+        assert!(matches!(
+            deliveries.next().await,
+            Some(Delivery::Resubscribed)
+        ));
+        publish(&env, "foo", "hello").await;
+        match deliveries.next().await {
+            Some(Delivery::Message(message)) => assert_eq!(message.payload, "hello"),
+            _ => panic!("Expected a message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn recovers_lost_pattern_subscriptions() {
+        let env = environment().await;
+        let pattern: ChannelPattern = "job:*".parse().unwrap();
+        let deliveries = recover_pattern(env.clone(), pattern, stream::empty().boxed());
+        futures::pin_mut!(deliveries);
+        assert!(matches!(
+            deliveries.next().await,
+            Some(Delivery::Resubscribed)
+        ));
+        publish(&env, "job:1", "hello").await;
+        match deliveries.next().await {
+            Some(Delivery::Message((channel_id, message))) => {
+                assert_eq!(channel_id, "job:1");
+                assert_eq!(message.payload, "hello");
+            }
+            _ => panic!("Expected a message"),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::settings;
+use anyhow::Context;
+use futures::{select, FutureExt};
+use parking_lot::RwLock;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile, sign, sign::CertifiedKey, ClientHello, NoClientAuth, ResolvesServerCert,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{info, warn};
+
+/// How often the certificate and key files are checked for changes.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Serves the certificate last loaded, so new connections pick up a reloaded one while
+/// existing connections carry on.
+struct Resolver {
+    key: RwLock<CertifiedKey>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.key.read().clone())
+    }
+}
+
+/// Builds an acceptor negotiating HTTP/2 or HTTP/1.1, reloading the certificate when its
+/// files change or on SIGHUP.
+pub fn acceptor(settings: &settings::ServerTls) -> anyhow::Result<TlsAcceptor> {
+    let resolver = Arc::new(Resolver {
+        key: RwLock::new(load(settings)?),
+    });
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = resolver.clone();
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    tokio::spawn(reload(settings.clone(), resolver));
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load(settings: &settings::ServerTls) -> anyhow::Result<CertifiedKey> {
+    let file = File::open(&settings.cert_file)
+        .with_context(|| format!("Failed to open TLS certificate {:?}", settings.cert_file))?;
+    let certs = pemfile::certs(&mut BufReader::new(file))
+        .map_err(|_| anyhow::anyhow!("Failed to parse TLS certificate {:?}", settings.cert_file))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No certificates found in {:?}",
+            settings.cert_file
+        ));
+    }
+
+    let pem = fs::read(&settings.key_file)
+        .with_context(|| format!("Failed to read TLS key {:?}", settings.key_file))?;
+    let key = pemfile::pkcs8_private_keys(&mut pem.as_slice())
+        .ok()
+        .filter(|keys| !keys.is_empty())
+        .or_else(|| pemfile::rsa_private_keys(&mut pem.as_slice()).ok())
+        .and_then(|keys| keys.into_iter().next())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "No PKCS#8 or RSA private key found in {:?}",
+                settings.key_file
+            )
+        })?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| anyhow::anyhow!("Unsupported TLS key type in {:?}", settings.key_file))?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// When the certificate and key files were last modified, to notice them being replaced.
+fn modified(settings: &settings::ServerTls) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    Some((
+        modified(&settings.cert_file).ok()?,
+        modified(&settings.key_file).ok()?,
+    ))
+}
+
+async fn reload(settings: settings::ServerTls, resolver: Arc<Resolver>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            warn!(
+                "Failed listening for SIGHUP, only watching TLS files: {}",
+                e
+            );
+            None
+        }
+    };
+    let mut last_modified = modified(&settings);
+    let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+    loop {
+        let hangup = async {
+            match &mut hangup {
+                Some(hangup) => hangup.recv().await,
+                None => futures::future::pending().await,
+            }
+        };
+        select! {
+            _ = hangup.fuse() => (),
+            _ = interval.tick().fuse() => {
+                let modified = modified(&settings);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+            }
+        }
+
+        match load(&settings) {
+            Ok(key) => {
+                *resolver.key.write() = key;
+                info!("Reloaded TLS certificate {:?}", settings.cert_file);
+            }
+            Err(e) => warn!(
+                "Failed reloading TLS certificate, keeping the last one: {:#}",
+                e
+            ),
+        }
+    }
+}
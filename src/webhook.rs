@@ -0,0 +1,260 @@
+use crate::{broker::Message, metrics, settings};
+use chrono::Utc;
+use futures::future::{AbortHandle, Abortable};
+use futures::{Stream, StreamExt};
+use parking_lot::Mutex;
+use reqwest::{header, StatusCode};
+use ring::hmac;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+
+/// Request header carrying the hex HMAC-SHA256 of `{x-signature-timestamp}.{body}`, keyed with
+/// the webhook's secret.
+const SIGNATURE_HEADER: &str = "x-signature";
+/// Request header carrying when the delivery was signed, in unix seconds, so endpoints can refuse
+/// replayed deliveries.
+const SIGNATURE_TIMESTAMP_HEADER: &str = "x-signature-timestamp";
+const CHANNEL_ID_HEADER: &str = "x-channel-id";
+const MESSAGE_ID_HEADER: &str = "x-message-id";
+const EVENT_HEADER: &str = "x-event";
+const TIMESTAMP_HEADER: &str = "x-timestamp";
+/// Prefixes message headers, as when publishing.
+const MESSAGE_HEADER_PREFIX: &str = "x-header-";
+
+/// Webhooks registered on this node, each POSTing its messages from a delivery worker.
+#[derive(Clone)]
+pub struct Webhooks {
+    settings: settings::Webhooks,
+    client: reqwest::Client,
+    workers: Arc<Mutex<HashMap<String, AbortHandle>>>,
+}
+
+pub struct Registration {
+    pub id: String,
+    /// Signs each delivery, only shown when registering.
+    pub secret: String,
+}
+
+struct Webhook {
+    url: reqwest::Url,
+    key: hmac::Key,
+}
+
+impl Webhooks {
+    pub fn new(settings: &settings::Webhooks) -> anyhow::Result<Self> {
+        if settings.queue_size == 0 {
+            return Err(anyhow::anyhow!("webhooks.queue_size must be at least 1"));
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.timeout))
+            .build()?;
+        Ok(Self {
+            settings: settings.clone(),
+            client,
+            workers: Default::default(),
+        })
+    }
+
+    /// Starts delivering messages, tagged with their channel IDs, to `url`.
+    pub fn register(
+        &self,
+        url: reqwest::Url,
+        deliveries: impl Stream<Item = (String, Message)> + Send + 'static,
+    ) -> Registration {
+        let id = nanoid::nanoid!();
+        let secret = nanoid::nanoid!(32);
+        let webhook = Webhook {
+            url,
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        };
+
+        let (handle, registration) = AbortHandle::new_pair();
+        self.workers.lock().insert(id.clone(), handle);
+        // Messages are queued as they arrive, so a slow endpoint doesn't hold up the channel.
+        let (queue, queued) = mpsc::channel(self.settings.queue_size);
+        let deliver = futures::future::join(
+            enqueue(deliveries, queue),
+            deliver(
+                self.client.clone(),
+                self.settings.clone(),
+                webhook,
+                ReceiverStream::new(queued),
+            ),
+        );
+        let workers = self.workers.clone();
+        let worker_id = id.clone();
+        tokio::spawn(async move {
+            let _ = Abortable::new(deliver, registration).await;
+            debug!("Webhook {:?} stopped", worker_id);
+            workers.lock().remove(&worker_id);
+        });
+        Registration { id, secret }
+    }
+
+    /// Stops a webhook, returning whether it was registered.
+    pub fn remove(&self, id: &str) -> bool {
+        match self.workers.lock().remove(id) {
+            Some(handle) => {
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Queues messages for delivery, dropping those that don't fit.
+async fn enqueue(
+    deliveries: impl Stream<Item = (String, Message)>,
+    queue: mpsc::Sender<(String, Message)>,
+) {
+    futures::pin_mut!(deliveries);
+    while let Some(delivery) = deliveries.next().await {
+        if queue.try_send(delivery).is_err() {
+            debug!("Webhook queue is full, dropping message");
+            metrics::WEBHOOK_DELIVERIES
+                .with_label_values(&["dropped"])
+                .inc();
+        }
+    }
+}
+
+/// POSTs each message in turn, retrying failed deliveries with backoff.
+async fn deliver(
+    client: reqwest::Client,
+    settings: settings::Webhooks,
+    webhook: Webhook,
+    deliveries: impl Stream<Item = (String, Message)>,
+) {
+    futures::pin_mut!(deliveries);
+    while let Some((channel_id, message)) = deliveries.next().await {
+        let mut delay = Duration::from_secs(settings.retry_delay);
+        let mut attempt = 1;
+        loop {
+            match webhook.post(&client, &channel_id, &message).await {
+                Ok(()) => {
+                    metrics::WEBHOOK_DELIVERIES
+                        .with_label_values(&["success"])
+                        .inc();
+                    break;
+                }
+                Err(Failure::Permanent(e)) => {
+                    warn!("Webhook delivery to {} failed: {:#}", webhook.url, e);
+                }
+                Err(Failure::Transient(e)) if attempt < settings.max_attempts => {
+                    debug!(
+                        "Webhook delivery to {} failed, retrying in {:?}: {:#}",
+                        webhook.url, delay, e
+                    );
+                    metrics::WEBHOOK_RETRIES.inc();
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                    continue;
+                }
+                Err(Failure::Transient(e)) => {
+                    warn!(
+                        "Webhook delivery to {} failed after {} attempts: {:#}",
+                        webhook.url, attempt, e
+                    );
+                }
+            }
+            metrics::WEBHOOK_DELIVERIES
+                .with_label_values(&["failure"])
+                .inc();
+            break;
+        }
+    }
+}
+
+enum Failure {
+    /// Worth retrying, like timeouts and server errors.
+    Transient(anyhow::Error),
+    /// The endpoint refused the message.
+    Permanent(anyhow::Error),
+}
+
+impl Webhook {
+    async fn post(
+        &self,
+        client: &reqwest::Client,
+        channel_id: &str,
+        message: &Message,
+    ) -> Result<(), Failure> {
+        // Signed with the time, so a captured delivery can't be passed off as a new one later.
+        let timestamp = Utc::now().timestamp().to_string();
+        let mut signed = Vec::with_capacity(timestamp.len() + 1 + message.payload.len());
+        signed.extend_from_slice(timestamp.as_bytes());
+        signed.push(b'.');
+        signed.extend_from_slice(&message.payload);
+        let signature = hmac::sign(&self.key, &signed);
+        let content_type = message
+            .content_type
+            .as_deref()
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM.as_ref());
+        let mut request = client
+            .post(self.url.clone())
+            .header(header::CONTENT_TYPE, content_type)
+            .header(CHANNEL_ID_HEADER, channel_id)
+            .header(
+                SIGNATURE_HEADER,
+                format!("sha256={}", hex::encode(signature.as_ref())),
+            )
+            .header(SIGNATURE_TIMESTAMP_HEADER, timestamp)
+            .body(message.payload.clone());
+        if let Some(id) = message.id {
+            request = request.header(MESSAGE_ID_HEADER, id.to_string());
+        }
+        if let Some(event) = &message.event {
+            request = request.header(EVENT_HEADER, event);
+        }
+        if let Some(timestamp) = message.timestamp {
+            request = request.header(TIMESTAMP_HEADER, timestamp);
+        }
+        for (name, value) in &message.headers {
+            request = request.header(format!("{}{}", MESSAGE_HEADER_PREFIX, name), value);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| Failure::Transient(e.into()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(Failure::Transient(anyhow::anyhow!("status {}", status)))
+        } else {
+            Err(Failure::Permanent(anyhow::anyhow!("status {}", status)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn drops_messages_past_the_queue() {
+        let dropped = metrics::WEBHOOK_DELIVERIES.with_label_values(&["dropped"]);
+        let before = dropped.get();
+        let (queue, mut queued) = mpsc::channel(2);
+        let deliveries = futures::stream::iter(["one", "two", "three"].map(|payload| {
+            (
+                "orders".to_owned(),
+                Message::new(Bytes::from_static(payload.as_bytes())),
+            )
+        }));
+        enqueue(deliveries, queue).await;
+
+        assert_eq!(dropped.get() - before, 1);
+        assert_eq!(queued.recv().await.unwrap().1.payload, "one");
+        assert_eq!(queued.recv().await.unwrap().1.payload, "two");
+        assert!(queued.recv().await.is_none());
+    }
+}
@@ -1,4 +1,5 @@
 use reqwest::StatusCode;
+use std::io::{Read, Write};
 use std::net::SocketAddr;
 
 use crate::util::CHANNEL_SECRET;
@@ -50,6 +51,43 @@ fn connect_subscriber(addr: &SocketAddr, channel_id: &str, token: &str) -> http:
         .expect("Failed to build subscriber request")
 }
 
+/// Reads a WebSocket frame from the server, returning its first byte and payload.
+fn read_frame(stream: &mut std::net::TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).unwrap();
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0; 8];
+            stream.read_exact(&mut len).unwrap();
+            u64::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    (head[0], payload)
+}
+
+/// Writes a final WebSocket frame, masked as clients must.
+fn write_frame(stream: &mut std::net::TcpStream, head: u8, payload: &[u8]) {
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![head];
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    } else {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    stream.write_all(&frame).unwrap();
+}
+
 server_test!(test_channel, "", |addr: SocketAddr| {
     let client = reqwest::blocking::Client::new();
     let api_key = "foo";
@@ -127,6 +165,16 @@ server_test!(test_channel, "", |addr: SocketAddr| {
     let (mut socket, response) = tungstenite::connect(request).unwrap();
     assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
 
+    // Check the channel stats count the subscriber
+    let response = client
+        .get(v1_url(&addr, "/channels/foo/stats"))
+        .header("x-api-key", api_key)
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: serde_json::Value = response.json().unwrap();
+    assert_eq!(json["subscribers"], 1, "{:?}", json);
+
     // Publish a message for the subscriber
     let response = send_message(&addr, "foo", "hello on foo", &token).unwrap();
     assert_eq!(response.status(), StatusCode::NO_CONTENT);
@@ -203,3 +251,1178 @@ server_test!(
         }
     }
 );
+
+server_test!(
+    test_channel_history,
+    // With history enabled for channels prefixed "job:"
+    "tests/settings/history.toml",
+    |addr: SocketAddr| {
+        let client = reqwest::blocking::Client::new();
+
+        // Create a channel with history
+        let response = client
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&serde_json::json!({ "channelId": "job:1" }))
+            .send()
+            .unwrap();
+        let json: serde_json::Value = response.json().unwrap();
+        let token = json["token"].as_str().unwrap();
+
+        // Publish while nobody is subscribed, each message gets an ID
+        let mut ids = vec![];
+        for message in &["one", "two", "three"] {
+            let response = send_message(&addr, "job:1", message, token).unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+            let id = response.headers()["x-message-id"].to_str().unwrap();
+            ids.push(id.to_string());
+        }
+
+        // A message nobody received is refused without keeping it, so it's not replayed
+        let response = client
+            .post(v1_url(&addr, "/channels/job:1?require_subscribers=true"))
+            .header("authorization", format!("Bearer {}", token))
+            .body("refused")
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        // Resume after the first message
+        let request = http::Request::builder()
+            .method("GET")
+            .uri(format!(
+                "ws://{}/webchannel/v1/channels/job:1?access_token={}&last_event_id={}",
+                addr, token, ids[0]
+            ))
+            .body(())
+            .unwrap();
+        let (mut socket, _) = tungstenite::connect(request).unwrap();
+
+        // I expect the missed messages replayed, followed by live messages
+        send_message(&addr, "job:1", "four", token).unwrap();
+        for expected in &["two", "three", "four"] {
+            let msg = socket.read_message().unwrap();
+            assert_eq!(
+                msg,
+                tungstenite::Message::Binary(expected.as_bytes().to_vec())
+            );
+        }
+
+        // Replay everything since the epoch
+        let request = http::Request::builder()
+            .method("GET")
+            .uri(format!(
+                "ws://{}/webchannel/v1/channels/job:1?access_token={}&since=0",
+                addr, token
+            ))
+            .body(())
+            .unwrap();
+        let (mut socket, _) = tungstenite::connect(request).unwrap();
+        for expected in &["one", "two", "three", "four"] {
+            let msg = socket.read_message().unwrap();
+            assert_eq!(
+                msg,
+                tungstenite::Message::Binary(expected.as_bytes().to_vec())
+            );
+        }
+
+        // An invalid ID is rejected
+        let request = http::Request::builder()
+            .method("GET")
+            .uri(format!(
+                "ws://{}/webchannel/v1/channels/job:1?access_token={}&last_event_id=nope",
+                addr, token
+            ))
+            .body(())
+            .unwrap();
+        assert!(tungstenite::connect(request).is_err());
+
+        // Channels outside the prefixes keep no history
+        let response = send_message(&addr, "foo", "hello", token).unwrap();
+        assert!(response.headers().get("x-message-id").is_none());
+    }
+);
+
+server_test!(test_publish_receivers, "", |addr: SocketAddr| {
+    let client = reqwest::blocking::Client::new();
+    let response = client
+        .post(v1_url(&addr, "/channels"))
+        .header("x-api-key", "foo")
+        .json(&serde_json::json!({ "channelId": "receivers" }))
+        .send()
+        .unwrap();
+    let json: serde_json::Value = response.json().unwrap();
+    let token = json["token"].as_str().unwrap();
+    let require_subscribers = |token: &str| {
+        client
+            .post(v1_url(
+                &addr,
+                "/channels/receivers?require_subscribers=true",
+            ))
+            .header("authorization", format!("Bearer {}", token))
+            .body("hello")
+            .send()
+            .unwrap()
+    };
+
+    // Nobody is subscribed yet
+    let response = send_message(&addr, "receivers", "hello", token).unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(response.headers()["x-receivers"], "0");
+    let response = require_subscribers(token);
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // Once subscribed, the message is received
+    let request = connect_subscriber(&addr, "receivers", token);
+    let (mut socket, _) = tungstenite::connect(request).unwrap();
+    // The subscription is made after the upgrade, wait for the broker to see it
+    let mut attempts = 0;
+    loop {
+        let stats: serde_json::Value = client
+            .get(v1_url(&addr, "/channels/receivers/stats"))
+            .header("x-api-key", "foo")
+            .send()
+            .unwrap()
+            .json()
+            .unwrap();
+        if stats["subscribers"] == 1 && stats["nodes"].as_u64().unwrap_or(1) == 1 {
+            break;
+        }
+        attempts += 1;
+        assert!(attempts < 50, "Subscription never registered: {:?}", stats);
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let response = require_subscribers(token);
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(response.headers()["x-receivers"], "1");
+    assert_eq!(
+        socket.read_message().unwrap(),
+        tungstenite::Message::Binary(b"hello".to_vec())
+    );
+});
+
+server_test!(
+    test_event_stream,
+    "tests/settings/history.toml",
+    |addr: SocketAddr| {
+        let client = reqwest::blocking::Client::new();
+        let response = client
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&serde_json::json!({ "channelId": "job:events" }))
+            .send()
+            .unwrap();
+        let json: serde_json::Value = response.json().unwrap();
+        let token = json["token"].as_str().unwrap();
+
+        let response = send_message(&addr, "job:events", "one", token).unwrap();
+        let id = response.headers()["x-message-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        send_message(&addr, "job:events", "two", token).unwrap();
+
+        // Resume after the first message, as EventSource does on reconnecting
+        let mut response = client
+            .get(v1_url(&addr, "/channels/job:events"))
+            .header("accept", "text/event-stream")
+            .header("authorization", format!("Bearer {}", token))
+            .header("last-event-id", id)
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+        let mut buf = [0; 256];
+        let read = response.read(&mut buf).unwrap();
+        let event = String::from_utf8_lossy(&buf[..read]);
+        assert!(event.starts_with("data:two\nid:"), "{:?}", event);
+    }
+);
+
+server_test!(
+    test_long_poll,
+    // With polls timing out after a second
+    "tests/settings/poll.toml",
+    |addr: SocketAddr| {
+        let client = reqwest::blocking::Client::new();
+        let response = client
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&serde_json::json!({ "channelId": "polled" }))
+            .send()
+            .unwrap();
+        let json: serde_json::Value = response.json().unwrap();
+        let token = json["token"].as_str().unwrap();
+        let poll = |cursor: Option<&str>| -> serde_json::Value {
+            let mut request = client
+                .get(v1_url(&addr, "/channels/polled/poll"))
+                .query(&[("access_token", token)]);
+            if let Some(cursor) = cursor {
+                request = request.query(&[("cursor", cursor)]);
+            }
+            let response = request.send().unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            response.json().unwrap()
+        };
+
+        // The first poll times out without messages, but starts buffering
+        let json = poll(None);
+        assert_eq!(json["messages"], serde_json::json!([]), "{:?}", json);
+        let cursor = json["cursor"].as_str().unwrap().to_string();
+
+        // Messages published between polls are returned by the next
+        send_message(&addr, "polled", "one", token).unwrap();
+        send_message(&addr, "polled", "two", token).unwrap();
+        let json = poll(Some(&cursor));
+        let expected = serde_json::json!([{ "data": "one" }, { "data": "two" }]);
+        assert_eq!(json["messages"], expected);
+
+        // Polling with the same cursor again returns the same messages
+        assert_eq!(poll(Some(&cursor))["messages"], expected);
+
+        // Each poll without a cursor starts buffering, up to the channel's limit
+        poll(None);
+        let response = client
+            .get(v1_url(&addr, "/channels/polled/poll"))
+            .query(&[("access_token", token)])
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+);
+
+server_test!(
+    test_upstream_messages,
+    // With client messages published upstream
+    "tests/settings/upstream.toml",
+    |addr: SocketAddr| {
+        let client = reqwest::blocking::Client::new();
+        let create_token = |channel_id: &str| -> String {
+            let response = client
+                .post(v1_url(&addr, "/channels"))
+                .header("x-api-key", "foo")
+                .json(&serde_json::json!({ "channelId": channel_id }))
+                .send()
+                .unwrap();
+            let json: serde_json::Value = response.json().unwrap();
+            json["token"].as_str().unwrap().to_string()
+        };
+
+        // A backend subscribes to the upstream channel, in envelopes
+        let up_token = create_token("job:up");
+        let request = http::Request::builder()
+            .uri(format!(
+                "ws://{}/webchannel/v1/channels/job:up?frames=json",
+                addr
+            ))
+            .header("authorization", format!("Bearer {}", up_token))
+            .body(())
+            .unwrap();
+        let (mut backend, _) = tungstenite::connect(request).unwrap();
+
+        // The browser's messages are published to it, typed by their frames
+        let token = create_token("job");
+        let (mut socket, _) =
+            tungstenite::connect(connect_subscriber(&addr, "job", &token)).unwrap();
+        socket
+            .write_message(tungstenite::Message::Text("cancel".into()))
+            .unwrap();
+        socket
+            .write_message(tungstenite::Message::Binary(vec![0xff]))
+            .unwrap();
+        let envelope = read_json(&mut backend);
+        assert_eq!(envelope["data"], "cancel");
+        assert_eq!(
+            envelope["headers"]["content-type"],
+            "text/plain; charset=utf-8"
+        );
+        assert!(envelope["ts"].is_u64());
+        let envelope = read_json(&mut backend);
+        assert_eq!(envelope["data"], "/w==");
+        assert_eq!(
+            envelope["headers"]["content-type"],
+            "application/octet-stream"
+        );
+
+        // Messages over the limit close the connection
+        socket
+            .write_message(tungstenite::Message::Text("x".repeat(17)))
+            .unwrap();
+        match socket.read_message().unwrap() {
+            tungstenite::Message::Close(Some(frame)) => {
+                assert_eq!(u16::from(frame.code), 1009)
+            }
+            other => panic!("Expected a close frame, got {:?}", other),
+        }
+    }
+);
+
+server_test!(
+    test_channel_subscriber_metrics,
+    // With subscribers counted by channel
+    "tests/settings/metrics.toml",
+    |addr: SocketAddr| {
+        let metrics = || {
+            reqwest::blocking::get(format!("http://{}/metrics", addr))
+                .unwrap()
+                .text()
+                .unwrap()
+        };
+        let counted = r#"webchannel_channel_local_subscribers{channel="counted"} 1"#;
+
+        let response = reqwest::blocking::Client::new()
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&serde_json::json!({ "channelId": "counted" }))
+            .send()
+            .unwrap();
+        let json: serde_json::Value = response.json().unwrap();
+        let token = json["token"].as_str().unwrap();
+        let (socket, _) =
+            tungstenite::connect(connect_subscriber(&addr, "counted", token)).unwrap();
+        assert!(metrics().contains(counted));
+
+        // The channel's series goes once its subscribers leave
+        drop(socket);
+        let mut attempts = 0;
+        while metrics().contains("channel=\"counted\"") {
+            attempts += 1;
+            assert!(attempts < 50, "Channel subscribers were never removed");
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+);
+
+server_test!(
+    test_keepalive,
+    // With pings every second, and idle connections closed after 3 seconds
+    "tests/settings/keepalive.toml",
+    |addr: SocketAddr| {
+        let client = reqwest::blocking::Client::new();
+        let response = client
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&serde_json::json!({ "channelId": "foo" }))
+            .send()
+            .unwrap();
+        let json: serde_json::Value = response.json().unwrap();
+        let token = json["token"].as_str().unwrap();
+
+        // Pings are answered by the client, which keeps the connection open
+        let (mut socket, _) =
+            tungstenite::connect(connect_subscriber(&addr, "foo", token)).unwrap();
+        assert!(socket.read_message().unwrap().is_ping());
+        assert!(socket.read_message().unwrap().is_ping());
+
+        // Without any messages it's closed once idle
+        loop {
+            match socket.read_message().unwrap() {
+                tungstenite::Message::Ping(_) => continue,
+                tungstenite::Message::Close(Some(frame)) => {
+                    assert_eq!(u16::from(frame.code), 1000);
+                    break;
+                }
+                other => panic!("Expected a close frame, got {:?}", other),
+            }
+        }
+    }
+);
+
+server_test!(
+    test_slow_consumer,
+    // With slow clients disconnected once a message is waiting to be sent
+    "tests/settings/slow_consumer.toml",
+    |addr: SocketAddr| {
+        let client = reqwest::blocking::Client::new();
+        let response = client
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&serde_json::json!({ "channelId": "foo" }))
+            .send()
+            .unwrap();
+        let json: serde_json::Value = response.json().unwrap();
+        let token = json["token"].as_str().unwrap();
+
+        // Publish more than the socket buffers hold, without reading any of it
+        let (mut socket, _) =
+            tungstenite::connect(connect_subscriber(&addr, "foo", token)).unwrap();
+        let published = 64;
+        let message = "x".repeat(500 * 1024);
+        for _ in 0..published {
+            let response = send_message(&addr, "foo", &message, token).unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+
+        // The client was disconnected before it received everything
+        let mut received = 0;
+        loop {
+            match socket.read_message().unwrap() {
+                tungstenite::Message::Binary(_) => received += 1,
+                tungstenite::Message::Close(Some(frame)) => {
+                    assert_eq!(u16::from(frame.code), 1008);
+                    break;
+                }
+                other => panic!("Expected a close frame, got {:?}", other),
+            }
+        }
+        assert!(received < published, "Received all {} messages", received);
+    }
+);
+
+server_test!(
+    test_deflate,
+    // With permessage-deflate, and messages over 64 bytes compressed
+    "tests/settings/deflate.toml",
+    |addr: SocketAddr| {
+        use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+        use std::io::{BufRead, BufReader};
+
+        let client = reqwest::blocking::Client::new();
+        let response = client
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&serde_json::json!({ "channelId": "foo" }))
+            .send()
+            .unwrap();
+        let json: serde_json::Value = response.json().unwrap();
+        let token = json["token"].as_str().unwrap();
+
+        // The offer is accepted, with the server's smaller window
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET /webchannel/v1/channels HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n",
+            addr
+        )
+        .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut head = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            head.push(line.trim_end().to_lowercase());
+        }
+        assert!(head[0].starts_with("http/1.1 101"), "{:?}", head);
+        assert!(
+            head.contains(
+                &"sec-websocket-extensions: permessage-deflate; server_max_window_bits=10".into()
+            ),
+            "{:?}",
+            head
+        );
+
+        // A compressed command is inflated
+        let command = serde_json::json!({
+            "type": "subscribe",
+            "channel": "foo",
+            "token": token,
+        })
+        .to_string();
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut compressed = Vec::with_capacity(command.len() + 64);
+        compress
+            .compress_vec(command.as_bytes(), &mut compressed, FlushCompress::Sync)
+            .unwrap();
+        compressed.truncate(compressed.len() - 4);
+        write_frame(&mut stream, 0x80 | 0x40 | 0x1, &compressed);
+
+        // The short reply isn't compressed
+        let (head, payload) = read_frame(&mut stream);
+        assert_eq!(head, 0x80 | 0x1);
+        let reply: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(reply["type"], "subscribed");
+
+        // The longer message is
+        let data = "x".repeat(500);
+        send_message(&addr, "foo", &data, token).unwrap();
+        let (head, payload) = read_frame(&mut stream);
+        assert_eq!(head, 0x80 | 0x40 | 0x1);
+        assert!(payload.len() < data.len());
+        let mut decompress = Decompress::new(false);
+        let mut inflated = Vec::with_capacity(4096);
+        decompress
+            .decompress_vec(
+                &[payload.as_slice(), &[0, 0, 0xff, 0xff]].concat(),
+                &mut inflated,
+                FlushDecompress::Sync,
+            )
+            .unwrap();
+        let reply: serde_json::Value = serde_json::from_slice(&inflated).unwrap();
+        assert_eq!(reply["type"], "message");
+        assert_eq!(reply["data"], data);
+    }
+);
+
+server_test!(test_text_frames, "", |addr: SocketAddr| {
+    let client = reqwest::blocking::Client::new();
+    let response = client
+        .post(v1_url(&addr, "/channels"))
+        .header("x-api-key", "foo")
+        .json(&serde_json::json!({ "channelId": "text" }))
+        .send()
+        .unwrap();
+    let json: serde_json::Value = response.json().unwrap();
+    let token = json["token"].as_str().unwrap();
+
+    let mut request = connect_subscriber(&addr, "text", token);
+    request.headers_mut().insert(
+        "sec-websocket-protocol",
+        http::HeaderValue::from_static("webchannel.v1.text"),
+    );
+    let (mut socket, response) = tungstenite::connect(request).unwrap();
+    assert_eq!(
+        response.headers()["sec-websocket-protocol"],
+        "webchannel.v1.text"
+    );
+
+    // UTF-8 payloads are sent as text, unless declared otherwise
+    send_message(&addr, "text", r#"{"percent":50}"#, token).unwrap();
+    assert_eq!(
+        socket.read_message().unwrap(),
+        tungstenite::Message::Text(r#"{"percent":50}"#.into())
+    );
+    client
+        .post(v1_url(&addr, "/channels/text"))
+        .header("authorization", format!("Bearer {}", token))
+        .header("content-type", "application/octet-stream")
+        .body("raw")
+        .send()
+        .unwrap();
+    assert_eq!(
+        socket.read_message().unwrap(),
+        tungstenite::Message::Binary(b"raw".to_vec())
+    );
+});
+
+server_test!(
+    test_json_envelope,
+    "tests/settings/history.toml",
+    |addr: SocketAddr| {
+        let client = reqwest::blocking::Client::new();
+        let response = client
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&serde_json::json!({ "channelId": "job:envelope" }))
+            .send()
+            .unwrap();
+        let json: serde_json::Value = response.json().unwrap();
+        let token = json["token"].as_str().unwrap();
+
+        let mut request = connect_subscriber(&addr, "job:envelope", token);
+        request.headers_mut().insert(
+            "sec-websocket-protocol",
+            http::HeaderValue::from_static("webchannel.v1.json"),
+        );
+        let (mut socket, response) = tungstenite::connect(request).unwrap();
+        assert_eq!(
+            response.headers()["sec-websocket-protocol"],
+            "webchannel.v1.json"
+        );
+
+        let response = client
+            .post(v1_url(&addr, "/channels/job:envelope?event=progress"))
+            .header("authorization", format!("Bearer {}", token))
+            .header("content-type", "application/json")
+            .header("x-header-publisher", "inventory")
+            .body(r#"{"percent":50}"#)
+            .send()
+            .unwrap();
+        let id = response.headers()["x-message-id"].to_str().unwrap();
+
+        let envelope: serde_json::Value = match socket.read_message().unwrap() {
+            tungstenite::Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("Expected a text frame, got {:?}", other),
+        };
+        assert!(envelope["ts"].is_u64(), "{:?}", envelope);
+        assert_eq!(
+            envelope,
+            serde_json::json!({
+                "id": id,
+                "channel": "job:envelope",
+                "event": "progress",
+                "ts": envelope["ts"],
+                "headers": {
+                    "content-type": "application/json",
+                    "publisher": "inventory",
+                },
+                "data": { "percent": 50 },
+            })
+        );
+    }
+);
+
+server_test!(test_multiplex, "", |addr: SocketAddr| {
+    let client = reqwest::blocking::Client::new();
+    let create_token = |channel_id: &str| -> String {
+        let response = client
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&serde_json::json!({ "channelId": channel_id }))
+            .send()
+            .unwrap();
+        let json: serde_json::Value = response.json().unwrap();
+        json["token"].as_str().unwrap().to_string()
+    };
+    let (one, two) = (create_token("job:1"), create_token("job:2"));
+
+    let (mut socket, _) =
+        tungstenite::connect(format!("ws://{}/webchannel/v1/channels", addr)).unwrap();
+    let mut command = |command: serde_json::Value| -> serde_json::Value {
+        socket
+            .write_message(tungstenite::Message::Text(command.to_string()))
+            .unwrap();
+        match socket.read_message().unwrap() {
+            tungstenite::Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("Expected a text frame, got {:?}", other),
+        }
+    };
+
+    // Each subscription is authorized by the channel's own token
+    let reply =
+        command(serde_json::json!({ "type": "subscribe", "channel": "job:1", "token": one }));
+    assert_eq!(
+        reply,
+        serde_json::json!({ "type": "subscribed", "channel": "job:1" })
+    );
+    let reply =
+        command(serde_json::json!({ "type": "subscribe", "channel": "job:2", "token": one }));
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["channel"], "job:2");
+    let reply =
+        command(serde_json::json!({ "type": "subscribe", "channel": "job:2", "token": two }));
+    assert_eq!(reply["type"], "subscribed");
+
+    // Messages are tagged with their channel
+    send_message(&addr, "job:2", "two", &two).unwrap();
+    let reply = command(serde_json::json!({ "type": "unsubscribe", "channel": "job:2" }));
+    assert_eq!(reply["type"], "message");
+    assert_eq!(reply["channel"], "job:2");
+    assert_eq!(reply["data"], "two");
+    match socket.read_message().unwrap() {
+        tungstenite::Message::Text(text) => assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            serde_json::json!({ "type": "unsubscribed", "channel": "job:2" })
+        ),
+        other => panic!("Expected a text frame, got {:?}", other),
+    }
+});
+
+server_test!(test_pattern_subscription, "", |addr: SocketAddr| {
+    let client = reqwest::blocking::Client::new();
+    let create_token = |request: serde_json::Value| -> reqwest::blocking::Response {
+        client
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&request)
+            .send()
+            .unwrap()
+    };
+    let response = create_token(serde_json::json!({ "channelPattern": "tenant:42:*" }));
+    let json: serde_json::Value = response.json().unwrap();
+    assert_eq!(json["channelPattern"], "tenant:42:*");
+    let token = json["token"].as_str().unwrap();
+
+    // Patterns can't reach outside their namespace
+    for pattern in ["tenant:4*", "tenant:*:*", "*"] {
+        let response = create_token(serde_json::json!({ "channelPattern": pattern }));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", pattern);
+    }
+    let request = connect_subscriber(&addr, "tenant:42:*", token);
+    assert!(tungstenite::connect(request).is_err());
+
+    let request = http::Request::builder()
+        .uri(format!("ws://{}/webchannel/v1/patterns/tenant:42:*", addr))
+        .header("authorization", format!("Bearer {}", token))
+        .body(())
+        .unwrap();
+    let (mut socket, _) = tungstenite::connect(request).unwrap();
+    let request = http::Request::builder()
+        .uri(format!("ws://{}/webchannel/v1/patterns/tenant:*", addr))
+        .header("authorization", format!("Bearer {}", token))
+        .body(())
+        .unwrap();
+    assert!(tungstenite::connect(request).is_err());
+
+    // Messages are tagged with the channel they were published to
+    let channel_token = create_token(serde_json::json!({ "channelId": "tenant:42:jobs" }))
+        .json::<serde_json::Value>()
+        .unwrap()["token"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    send_message(&addr, "tenant:43:jobs", "other", &channel_token).unwrap();
+    send_message(&addr, "tenant:42:jobs", "hello", &channel_token).unwrap();
+    let envelope: serde_json::Value = match socket.read_message().unwrap() {
+        tungstenite::Message::Text(text) => serde_json::from_str(&text).unwrap(),
+        other => panic!("Expected a text frame, got {:?}", other),
+    };
+    assert_eq!(envelope["channel"], "tenant:42:jobs");
+    assert_eq!(envelope["data"], "hello");
+});
+
+/// Reads an HTTP request from a webhook delivery, returning its lowercased headers and body.
+fn read_request(stream: &mut std::net::TcpStream) -> (Vec<(String, String)>, Vec<u8>) {
+    use std::io::BufRead;
+    let mut reader = std::io::BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("POST /hook "), "{}", line);
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        match line.trim_end().split_once(':') {
+            Some((name, value)) => headers.push((name.to_lowercase(), value.trim().to_string())),
+            None => break,
+        }
+    }
+    let len: usize = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .map_or(0, |(_, value)| value.parse().unwrap());
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).unwrap();
+    (headers, body)
+}
+
+server_test!(
+    test_webhooks,
+    "tests/settings/webhooks.toml",
+    |addr: SocketAddr| {
+        // Fails the first delivery, so it's retried
+        let endpoint = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint_addr = endpoint.local_addr().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for status in ["500 Internal Server Error", "200 OK"] {
+                let (mut stream, _) = endpoint.accept().unwrap();
+                let request = read_request(&mut stream);
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                tx.send(request).unwrap();
+            }
+        });
+
+        let client = reqwest::blocking::Client::new();
+        let register = |request: serde_json::Value| {
+            client
+                .post(v1_url(&addr, "/webhooks"))
+                .header("x-api-key", "foo")
+                .json(&request)
+                .send()
+                .unwrap()
+        };
+        let response = register(serde_json::json!({ "url": "ftp://example.com" }));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = register(serde_json::json!({
+            "url": format!("http://{}/hook", endpoint_addr),
+            "channelId": "orders",
+        }));
+        assert_eq!(response.status(), StatusCode::OK);
+        let json: serde_json::Value = response.json().unwrap();
+        let webhook_id = json["webhookId"].as_str().unwrap().to_string();
+        let secret = json["secret"].as_str().unwrap().to_string();
+
+        let token = client
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&serde_json::json!({ "channelId": "orders" }))
+            .send()
+            .unwrap()
+            .json::<serde_json::Value>()
+            .unwrap()["token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        send_message(&addr, "orders", "hello", &token).unwrap();
+
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+        for _ in 0..2 {
+            let (headers, body) = rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+            let header = |name: &str| {
+                headers
+                    .iter()
+                    .find(|(header, _)| header == name)
+                    .map(|(_, value)| value.clone())
+            };
+            assert_eq!(body, b"hello");
+            assert_eq!(header("x-channel-id").as_deref(), Some("orders"));
+            // Signed with the time, which is recent
+            let timestamp = header("x-signature-timestamp").unwrap();
+            let age = chrono::Utc::now().timestamp() - timestamp.parse::<i64>().unwrap();
+            assert!((0..60).contains(&age), "{}", age);
+            let signed = format!("{}.hello", timestamp);
+            let signature = ring::hmac::sign(&key, signed.as_bytes());
+            assert_eq!(
+                header("x-signature"),
+                Some(format!("sha256={}", hex::encode(signature)))
+            );
+        }
+
+        let delete = || {
+            client
+                .delete(v1_url(&addr, &format!("/webhooks/{}", webhook_id)))
+                .header("x-api-key", "foo")
+                .send()
+                .unwrap()
+                .status()
+        };
+        assert_eq!(delete(), StatusCode::NO_CONTENT);
+        assert_eq!(delete(), StatusCode::NOT_FOUND);
+    }
+);
+
+server_test!(test_tls, "tests/settings/tls.toml", |addr: SocketAddr| {
+    // rustls rejects IP addresses as server names, as sent for IP URLs
+    let url = format!("https://localhost:{}/healthz", addr.port());
+    let client = reqwest::blocking::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let response = client.get(&url).send().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.version(), reqwest::Version::HTTP_11);
+
+    let client = reqwest::blocking::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .http2_prior_knowledge()
+        .build()
+        .unwrap();
+    let response = client.get(&url).send().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+
+    // Plain HTTP isn't served alongside
+    assert!(reqwest::blocking::get(format!("http://{}/healthz", addr)).is_err());
+});
+
+#[test]
+fn test_graceful_shutdown() {
+    let (mut handle, addr) = crate::util::start_server("");
+    let result = std::panic::catch_unwind(|| {
+        let client = reqwest::blocking::Client::new();
+        let ready = || {
+            client
+                .get(format!("http://{}/readyz", addr))
+                .send()
+                .unwrap()
+                .status()
+        };
+        assert_eq!(ready(), StatusCode::OK);
+        let token = client
+            .post(v1_url(&addr, "/channels"))
+            .header("x-api-key", "foo")
+            .json(&serde_json::json!({ "channelId": "foo" }))
+            .send()
+            .unwrap()
+            .json::<serde_json::Value>()
+            .unwrap()["token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let (mut socket, _) =
+            tungstenite::connect(connect_subscriber(&addr, "foo", &token)).unwrap();
+
+        let status = std::process::Command::new("kill")
+            .args(["-TERM", &handle.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        // Subscribers are told the server is going away
+        match socket.read_message().unwrap() {
+            tungstenite::Message::Close(Some(frame)) => assert_eq!(u16::from(frame.code), 1001),
+            other => panic!("Expected a close frame, got {:?}", other),
+        }
+    });
+    let exited = (0..50).find_map(|_| {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        handle.try_wait().unwrap()
+    });
+    if exited.is_none() {
+        handle.kill().unwrap();
+    }
+    result.unwrap();
+    assert!(exited.unwrap().success());
+}
+
+fn read_json<S: std::io::Read + std::io::Write>(
+    socket: &mut tungstenite::WebSocket<S>,
+) -> serde_json::Value {
+    match socket.read_message().unwrap() {
+        tungstenite::Message::Text(text) => serde_json::from_str(&text).unwrap(),
+        other => panic!("Expected a text frame, got {:?}", other),
+    }
+}
+
+fn pusher_sign(data: &str) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"app-secret");
+    hex::encode(ring::hmac::sign(&key, data.as_bytes()))
+}
+
+/// Triggers an event through the Pusher API, signed as its server libraries do.
+fn pusher_trigger(addr: &SocketAddr, body: &serde_json::Value, secret_ok: bool) -> StatusCode {
+    let body = body.to_string();
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let query = format!(
+        "auth_key=app-key&auth_timestamp={}&auth_version=1.0&body_md5={:x}",
+        timestamp,
+        md5::compute(&body)
+    );
+    let mut signature = pusher_sign(&format!("POST\n/apps/1/events\n{}", query));
+    if !secret_ok {
+        signature = pusher_sign("something else");
+    }
+    reqwest::blocking::Client::new()
+        .post(format!(
+            "http://{}/apps/1/events?{}&auth_signature={}",
+            addr, query, signature
+        ))
+        .header("content-type", "application/json")
+        .body(body)
+        .send()
+        .unwrap()
+        .status()
+}
+
+server_test!(
+    test_pusher,
+    "tests/settings/pusher.toml",
+    |addr: SocketAddr| {
+        let (mut socket, _) =
+            tungstenite::connect(format!("ws://{}/app/app-key?protocol=7", addr)).unwrap();
+        let established = read_json(&mut socket);
+        assert_eq!(established["event"], "pusher:connection_established");
+        let data: serde_json::Value =
+            serde_json::from_str(established["data"].as_str().unwrap()).unwrap();
+        let socket_id = data["socket_id"].as_str().unwrap().to_string();
+
+        let mut send_event = |event: serde_json::Value| -> serde_json::Value {
+            socket
+                .write_message(tungstenite::Message::text(event.to_string()))
+                .unwrap();
+            read_json(&mut socket)
+        };
+        let reply = send_event(serde_json::json!({"event": "pusher:ping", "data": {}}));
+        assert_eq!(reply["event"], "pusher:pong");
+        let reply = send_event(serde_json::json!({
+            "event": "pusher:subscribe",
+            "data": {"channel": "orders"},
+        }));
+        assert_eq!(reply["event"], "pusher_internal:subscription_succeeded");
+        assert_eq!(reply["channel"], "orders");
+
+        // Private channels need a subscription signed with the app secret
+        let reply = send_event(serde_json::json!({
+            "event": "pusher:subscribe",
+            "data": {"channel": "private-orders", "auth": "app-key:00"},
+        }));
+        assert_eq!(reply["event"], "pusher:subscription_error");
+        assert_eq!(reply["data"]["status"], 401);
+        let auth = format!(
+            "app-key:{}",
+            pusher_sign(&format!("{}:private-orders", socket_id))
+        );
+        let reply = send_event(serde_json::json!({
+            "event": "pusher:subscribe",
+            "data": {"channel": "private-orders", "auth": auth},
+        }));
+        assert_eq!(reply["event"], "pusher_internal:subscription_succeeded");
+
+        let event = serde_json::json!({
+            "name": "created",
+            "channels": ["orders", "private-orders"],
+            "data": "{\"id\":1}",
+        });
+        assert_eq!(
+            pusher_trigger(&addr, &event, false),
+            StatusCode::UNAUTHORIZED
+        );
+        // Not sent back to the connection that triggered it
+        let own_event = serde_json::json!({
+            "name": "created",
+            "channel": "orders",
+            "data": "{\"id\":0}",
+            "socket_id": socket_id,
+        });
+        assert_eq!(pusher_trigger(&addr, &own_event, true), StatusCode::OK);
+        // Webchannel's own channels aren't Pusher channels of the same name
+        publish_subscribe(&addr, "orders");
+        assert_eq!(pusher_trigger(&addr, &event, true), StatusCode::OK);
+
+        let mut channels = vec![];
+        for _ in 0..2 {
+            let event = read_json(&mut socket);
+            assert_eq!(event["event"], "created");
+            assert_eq!(event["data"], "{\"id\":1}");
+            channels.push(event["channel"].as_str().unwrap().to_string());
+        }
+        channels.sort();
+        assert_eq!(channels, ["orders", "private-orders"]);
+
+        // Pusher channels are relayed through webchannel channels under their own prefix
+        publish_subscribe(&addr, "pusher:orders");
+        let event = read_json(&mut socket);
+        assert_eq!(event["event"], "message");
+        assert_eq!(event["channel"], "orders");
+        assert_eq!(event["data"], "hello");
+    }
+);
+
+/// Creates a channel, subscribes to it and checks a published message arrives, returning the
+/// channel's token.
+fn publish_subscribe(addr: &SocketAddr, channel_id: &str) -> String {
+    let client = reqwest::blocking::Client::new();
+    let response = client
+        .post(v1_url(addr, "/channels"))
+        .header("x-api-key", "foo")
+        .json(&serde_json::json!({ "channelId": channel_id }))
+        .send()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: serde_json::Value = response.json().unwrap();
+    let token = json["token"].as_str().unwrap().to_string();
+
+    let (mut socket, _) =
+        tungstenite::connect(connect_subscriber(addr, channel_id, &token)).unwrap();
+    let response = send_message(addr, channel_id, "hello", &token).unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        socket.read_message().unwrap(),
+        tungstenite::Message::Binary(b"hello".to_vec())
+    );
+    token
+}
+
+broker_test!(
+    test_redis_broker,
+    // With the Redis backend, and history for channels prefixed "job:"
+    "tests/settings/redis.toml",
+    "127.0.0.1:6379",
+    |addr: SocketAddr| {
+        publish_subscribe(&addr, "foo");
+
+        // Missed messages are replayed from the channel's stream
+        let token = publish_subscribe(&addr, "job:redis");
+        let mut ids = vec![];
+        for message in &["one", "two"] {
+            let response = send_message(&addr, "job:redis", message, &token).unwrap();
+            let id = response.headers()["x-message-id"].to_str().unwrap();
+            ids.push(id.to_string());
+        }
+        let request = http::Request::builder()
+            .method("GET")
+            .uri(format!(
+                "ws://{}/webchannel/v1/channels/job:redis?access_token={}&last_event_id={}",
+                addr, token, ids[0]
+            ))
+            .body(())
+            .unwrap();
+        let (mut socket, _) = tungstenite::connect(request).unwrap();
+        assert_eq!(
+            socket.read_message().unwrap(),
+            tungstenite::Message::Binary(b"two".to_vec())
+        );
+
+        // Redis subscribers get payloads as published, and webchannel subscribers their metadata
+        let mut redis = std::net::TcpStream::connect("127.0.0.1:6379").unwrap();
+        redis
+            .write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$14\r\nwc:channel:raw\r\n")
+            .unwrap();
+        let mut reply = [0; 44];
+        redis.read_exact(&mut reply).unwrap();
+        let token = publish_subscribe(&addr, "raw");
+        let mut request = connect_subscriber(&addr, "raw", &token);
+        request.headers_mut().insert(
+            "sec-websocket-protocol",
+            http::HeaderValue::from_static("webchannel.v1.text"),
+        );
+        let (mut socket, _) = tungstenite::connect(request).unwrap();
+        reqwest::blocking::Client::new()
+            .post(v1_url(&addr, "/channels/raw"))
+            .header("authorization", format!("Bearer {}", token))
+            .header("content-type", "application/octet-stream")
+            .body("raw")
+            .send()
+            .unwrap();
+        assert_eq!(
+            socket.read_message().unwrap(),
+            tungstenite::Message::Binary(b"raw".to_vec())
+        );
+        let mut reply = vec![];
+        while !reply.ends_with(b"$3\r\nraw\r\n") {
+            let mut buf = [0; 64];
+            let read = redis.read(&mut buf).unwrap();
+            assert_ne!(read, 0);
+            reply.extend_from_slice(&buf[..read]);
+        }
+        assert_eq!(
+            reply,
+            b"*3\r\n$7\r\nmessage\r\n$14\r\nwc:channel:raw\r\n$5\r\nhello\r\n\
+              *3\r\n$7\r\nmessage\r\n$14\r\nwc:channel:raw\r\n$3\r\nraw\r\n"
+        );
+    }
+);
+
+broker_test!(
+    test_nats_broker,
+    // With the NATS backend
+    "tests/settings/nats.toml",
+    "127.0.0.1:4222",
+    |addr: SocketAddr| {
+        publish_subscribe(&addr, "foo");
+        publish_subscribe(&addr, "job:nats");
+    }
+);
+
+broker_test!(
+    test_postgres_broker,
+    // With the Postgres backend
+    "tests/settings/postgres.toml",
+    "127.0.0.1:5432",
+    |addr: SocketAddr| {
+        publish_subscribe(&addr, "foo");
+        let token = publish_subscribe(&addr, "job:postgres");
+
+        // Metadata travels with the notification
+        let request = http::Request::builder()
+            .uri(format!(
+                "ws://{}/webchannel/v1/channels/job:postgres?frames=json",
+                addr
+            ))
+            .header("authorization", format!("Bearer {}", token))
+            .body(())
+            .unwrap();
+        let (mut socket, _) = tungstenite::connect(request).unwrap();
+        let response = reqwest::blocking::Client::new()
+            .post(v1_url(&addr, "/channels/job:postgres?event=created"))
+            .header("authorization", format!("Bearer {}", token))
+            .header("content-type", "application/json")
+            .body(r#"{"id":1}"#)
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let envelope = read_json(&mut socket);
+        assert_eq!(envelope["event"], "created");
+        assert_eq!(envelope["data"], serde_json::json!({"id": 1}));
+        assert_eq!(envelope["headers"]["content-type"], "application/json");
+        assert!(envelope["ts"].is_u64());
+    }
+);
@@ -19,3 +19,36 @@ macro_rules! aw {
         tokio_test::block_on($e)
     };
 }
+
+/// Like `server_test!`, but skipped when nothing is listening on the broker's address, for
+/// backends that need an external server. Fails instead when `CI` is set, so CI can't pass
+/// without the broker.
+#[macro_export]
+macro_rules! broker_test {
+    ($name:ident, $config_file:tt, $broker_addr:tt, $fun:expr) => {
+        #[test]
+        fn $name() {
+            if !$crate::util::broker_available($broker_addr) {
+                if std::env::var_os("CI").is_some() {
+                    panic!(
+                        "{} needs a broker listening on {}",
+                        stringify!($name),
+                        $broker_addr
+                    );
+                }
+                eprintln!(
+                    "Skipping {}, nothing is listening on {}",
+                    stringify!($name),
+                    $broker_addr
+                );
+                return;
+            }
+            let (mut handle, addr) = $crate::util::start_server($config_file);
+            let result = std::panic::catch_unwind(|| {
+                $fun(addr);
+            });
+            handle.kill().unwrap();
+            result.unwrap();
+        }
+    };
+}
@@ -1,5 +1,5 @@
 use std::io;
-use std::net::{SocketAddr, TcpListener};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::process::{Child, Command};
 use std::thread::sleep;
 use std::time::Duration;
@@ -32,3 +32,9 @@ pub fn start_server(config_file: &str) -> (Child, SocketAddr) {
 
     (handle, addr)
 }
+
+/// Whether a broker server is listening on `addr`, for tests needing one.
+pub fn broker_available(addr: &str) -> bool {
+    let addr: SocketAddr = addr.parse().expect("Invalid broker address");
+    TcpStream::connect_timeout(&addr, Duration::from_millis(500)).is_ok()
+}